@@ -0,0 +1,133 @@
+//! Compares the throughput of the byte level metric parser with the regex based parser it
+//! replaced. Run it with `cargo bench`.
+#[macro_use]
+extern crate lazy_static;
+
+extern crate capella;
+extern crate regex;
+
+use std::hint::black_box;
+use std::rc::Rc;
+use std::str;
+use std::time::{Duration, Instant};
+
+use regex::Regex;
+
+use capella::parse::{self, Tag};
+
+// A mix of the lines clients usually send, including tags, sample rates and packed values.
+const LINES: &[&[u8]] = &[b"test:-1|g",
+                          b"some.other.metric:11|c",
+                          b"set:99|s",
+                          b"users.unique:alice@example.com|s",
+                          b"counter:1|c|@0.5",
+                          b"api.request.latency:12.5|ms|#env:prod,region:us-west,canary",
+                          b"queue.lag:250:310:198|d|@0.25|#env:prod",
+                          b"bad line"];
+
+const ITERATIONS: usize = 1_000_000;
+
+// The parts of a metric the regex parser produced, which owned every string it returned.
+#[allow(dead_code)]
+struct OwnedMetric {
+    name: Rc<String>,
+    value: f64,
+    member: Option<String>,
+    metric_type: String,
+    sample_rate: Option<f64>,
+    tags: Vec<Tag>,
+}
+
+// The regex based parser as it was before the byte level parser replaced it.
+fn regex_parse_metrics(packet: &[u8]) -> Option<Vec<OwnedMetric>> {
+    lazy_static! {
+        static ref PATTERN: Regex = Regex::new(r"(?x)
+            \A(?P<name>[\w\.]+):
+            (?P<val>[^|]+)
+            \|(?P<type>\w+)
+            (\|@(?P<rate>\d+\.\d+))?
+            (\|\#(?P<tags>[^,|]+(,[^,|]+)*))?\z").unwrap();
+
+        static ref NUMBER: Regex = Regex::new(r"(?x)
+            \A((?P<sign>\-|\+))?
+            (?P<num>([0-9]*[.])?[0-9]+)\z").unwrap();
+    }
+
+    if !PATTERN.is_match(str::from_utf8(packet).ok()?) {
+        return None;
+    }
+    let caps = PATTERN.captures(str::from_utf8(packet).unwrap()).unwrap();
+
+    let name = Rc::new(String::from(caps.name("name").unwrap().as_str()));
+    let metric_type = caps.name("type").unwrap().as_str();
+    let sample_rate = match caps.name("rate") {
+        Some(rate) => Some(rate.as_str().parse::<f64>().ok()?),
+        None => None,
+    };
+    let tags = match caps.name("tags") {
+        Some(tags) => {
+            tags.as_str().split(',').map(|t| t.parse::<Tag>().ok()).collect::<Option<_>>()?
+        }
+        None => Vec::new(),
+    };
+
+    let mut metrics = Vec::new();
+    for value in caps.name("val").unwrap().as_str().split(':') {
+        let mut metric = OwnedMetric {
+            name: name.clone(),
+            value: 0.0,
+            member: None,
+            metric_type: String::from(metric_type),
+            sample_rate,
+            tags: tags.clone(),
+        };
+        if metric_type == "s" {
+            metric.member = Some(String::from(value));
+        } else {
+            let number = NUMBER.captures(value)?;
+            metric.value = number.name("num").unwrap().as_str().parse::<f64>().ok()?;
+            if number.name("sign").is_some_and(|s| s.as_str() == "-") {
+                metric.value *= -1.0;
+            }
+        }
+        metrics.push(metric);
+    }
+
+    Some(metrics)
+}
+
+// Parse every sample line `ITERATIONS` times and return how long it took and how many metrics
+// were parsed.
+fn run<F>(mut parse: F) -> (Duration, usize)
+    where F: FnMut(&[u8]) -> usize
+{
+    let start = Instant::now();
+    let mut parsed = 0;
+    for _ in 0..ITERATIONS {
+        for line in LINES {
+            parsed += parse(black_box(line));
+        }
+    }
+
+    (start.elapsed(), parsed)
+}
+
+fn report(name: &str, (elapsed, parsed): (Duration, usize)) -> f64 {
+    let lines_per_sec = (ITERATIONS * LINES.len()) as f64 / elapsed.as_secs_f64();
+    println!("{:<8} {:>10.0} lines/s {:>10} metrics in {:?}",
+             name,
+             lines_per_sec,
+             parsed,
+             elapsed);
+
+    lines_per_sec
+}
+
+fn main() {
+    let old = report("regex",
+                     run(|line| regex_parse_metrics(line).map_or(0, |m| black_box(m).len())));
+    let new = report("bytes",
+                     run(|line| parse::parse_metrics(line).map_or(0, |m| black_box(m).len())));
+
+    println!("the byte parser is {:.1}x faster", new / old);
+}
@@ -2,11 +2,236 @@
 //! to be forwarded stats from capella.
 #![deny(missing_docs)]
 
-use cache::CapellaCache;
+use std::io::{self, BufReader, Read};
+use std::net::SocketAddr;
+use std::time::Duration;
 
-/// Backend defines a generic backend that can be forwarded metrics from capella.
+use futures::{future, stream, Future, Stream};
+
+use tokio_core::net::{TcpStream, UdpSocket};
+use tokio_core::reactor::{Handle, Timeout};
+
+use tokio_io::io::{read_until, write_all};
+
+use snapshot::FlushSnapshot;
+
+/// How many seconds a flush may take to connect to a server when no timeout is given. It is kept
+/// well below the usual flush interval so a hung server does not pile up flushes.
+pub const DEFAULT_CONNECT_TIMEOUT_SECS: u64 = 5;
+
+/// How many seconds a flush may take to write its payload when no timeout is given.
+pub const DEFAULT_WRITE_TIMEOUT_SECS: u64 = 5;
+
+// The longest HTTP status line we are willing to read back from a server.
+const MAX_STATUS_LINE: u64 = 1024;
+
+// Datagrams are kept below a typical MTU so they are not fragmented.
+const MAX_DATAGRAM_SIZE: usize = 1400;
+
+/// `Flush` is the work a backend does to deliver a single flush. It resolves once the backend is
+/// done with the flush, or fails with the reason it could not deliver it.
+pub type Flush = Box<dyn Future<Item = (), Error = io::Error>>;
+
+/// Return a flush that has nothing left to do, for backends that finish their work right away.
+pub fn done() -> Flush {
+    Box::new(future::ok(()))
+}
+
+/// Return the error for a backend that was asked to flush before it was started.
+pub fn not_started() -> io::Error {
+    io::Error::other("the backend was flushed before it was started")
+}
+
+// Pack whole newline terminated lines into datagrams no larger than `MAX_DATAGRAM_SIZE`. A single
+// line that is larger than the limit is sent on its own.
+fn make_datagrams(lines: &[u8]) -> Vec<Vec<u8>> {
+    let mut datagrams = Vec::new();
+    let mut current = Vec::new();
+
+    for line in lines.split(|b| *b == b'\n').filter(|l| !l.is_empty()) {
+        if !current.is_empty() && current.len() + line.len() + 1 > MAX_DATAGRAM_SIZE {
+            datagrams.push(current);
+            current = Vec::new();
+        }
+        current.extend_from_slice(line);
+        current.push(b'\n');
+    }
+    if !current.is_empty() {
+        datagrams.push(current);
+    }
+
+    datagrams
+}
+
+// Fail a future with a `TimedOut` error if it has not finished within the given duration. The
+// future is dropped when it times out, which closes any connection it holds.
+pub(crate) fn with_timeout<F>(future: F,
+                              duration: Duration,
+                              handle: &Handle,
+                              doing: &str)
+                              -> Box<dyn Future<Item = F::Item, Error = io::Error>>
+    where F: Future<Error = io::Error> + 'static
+{
+    let reason = format!("timed out {} after {:?}", doing, duration);
+    let timeout = match Timeout::new(duration, handle) {
+        Ok(timeout) => timeout,
+        Err(e) => return Box::new(future::err(e)),
+    };
+    let timeout = timeout.and_then(|_| Err(io::Error::new(io::ErrorKind::TimedOut, reason)));
+
+    Box::new(future.select(timeout)
+        .map(|(item, _)| item)
+        .map_err(|(e, _)| e))
+}
+
+// Post the body to an HTTP server over a new connection and fail unless it answers with a 2xx
+// status. The response is read within the write timeout, and only its status line is looked at.
+pub(crate) fn post_http(addr: SocketAddr,
+                        host: &str,
+                        path: &str,
+                        content_type: &str,
+                        body: &str,
+                        (connect_timeout, write_timeout): (Duration, Duration),
+                        handle: &Handle)
+                        -> Flush {
+    let request = format!("POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: {}\r\n\
+                           Content-Length: {}\r\nConnection: close\r\n\r\n{}",
+                          path,
+                          host,
+                          content_type,
+                          body.len(),
+                          body);
+    let connecting = format!("connecting to {}", host);
+    let posting = format!("posting to {}{}", host, path);
+    let rejected = format!("{}{} rejected the post", host, path);
+    let inner = handle.clone();
+
+    let connect = TcpStream::connect(&addr, handle);
+    Box::new(with_timeout(connect, connect_timeout, handle, &connecting)
+        .and_then(move |out| {
+            let exchange = write_all(out, request).and_then(|(out, _)| {
+                read_until(BufReader::new(out.take(MAX_STATUS_LINE)), b'\n', Vec::new())
+            });
+            with_timeout(exchange, write_timeout, &inner, &posting)
+        })
+        .and_then(move |(_, status)| {
+            let status = String::from_utf8_lossy(&status);
+            if status.split_whitespace().nth(1).is_none_or(|c| !c.starts_with('2')) {
+                return Err(io::Error::other(format!("{}: {}", rejected, status.trim())));
+            }
+            Ok(())
+        }))
+}
+
+// Pack the lines into datagrams and send them from a new socket.
+pub(crate) fn send_datagrams(addr: SocketAddr,
+                             lines: &[u8],
+                             write_timeout: Duration,
+                             handle: &Handle)
+                             -> Flush {
+    let local: SocketAddr = if addr.is_ipv4() {
+        "0.0.0.0:0".parse().unwrap()
+    } else {
+        "[::]:0".parse().unwrap()
+    };
+    let socket = match UdpSocket::bind(&local, handle) {
+        Ok(socket) => socket,
+        Err(e) => return Box::new(future::err(e)),
+    };
+
+    let send = stream::iter_ok(make_datagrams(lines)).fold(socket, move |socket, datagram| {
+        socket.send_dgram(datagram, addr).map(|(socket, _)| socket)
+    });
+    let sending = format!("sending datagrams to {}", addr);
+    Box::new(with_timeout(send, write_timeout, handle, &sending).map(|_| ()))
+}
+
+/// Backend defines a generic backend that can be forwarded metrics from capella. Several
+/// backends can be configured at once, and each one is handed the same read-only snapshot.
 pub trait Backend {
-    /// Flush metrics accepts a `CapellaCache` type and forwards it to the backend that
-    /// implements it.
-    fn purge_metrics(&self, cache: &mut CapellaCache);
+    /// Return the name of the backend, which is used in logs and in the statistics capella keeps
+    /// about each backend's flushes.
+    fn name(&self) -> &'static str;
+
+    /// Start is called once with a handle to the event loop before any metrics are flushed.
+    /// Backends that need to run their own futures, such as a listener, can spawn them here.
+    fn start(&mut self, _handle: &Handle) {}
+
+    /// Purge events is called on every flush, before `purge_metrics`, with the events and service
+    /// checks received since the last flush. Backends that forward them, such as a webhook, read
+    /// them from the snapshot here.
+    fn purge_events(&self, _snapshot: &FlushSnapshot) -> Flush {
+        done()
+    }
+
+    /// Purge metrics is called on every flush with the metrics aggregated since the last flush.
+    /// The backend reads what it needs from the snapshot before returning, since the snapshot is
+    /// dropped as soon as every backend has been handed it.
+    fn purge_metrics(&self, snapshot: &FlushSnapshot) -> Flush;
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{ErrorKind, Read, Write};
+    use std::net::TcpListener;
+    use std::thread;
+    use std::time::Duration;
+
+    use futures::future;
+
+    use tokio_core::reactor::Core;
+
+    use super::{make_datagrams, post_http, with_timeout};
+
+    #[test]
+    fn datagrams_split_on_lines() {
+        let line = "a".repeat(999);
+        let lines = format!("{}\n{}\n", line, line);
+        let datagrams = make_datagrams(lines.as_bytes());
+
+        assert_eq!(datagrams.len(), 2);
+        assert_eq!(datagrams[0].len(), 1000);
+    }
+
+    #[test]
+    fn futures_time_out() {
+        let mut core = Core::new().unwrap();
+        let handle = core.handle();
+        let hung = future::empty::<(), _>();
+        let res = core.run(with_timeout(hung, Duration::from_millis(10), &handle, "writing"));
+        assert_eq!(res.unwrap_err().kind(), ErrorKind::TimedOut);
+    }
+
+    // Post to a server that answers every request with the given status line, and return the
+    // result along with the request it received.
+    fn post_to(status: &'static str) -> (Result<(), ErrorKind>, String) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            let (mut sock, _) = listener.accept().unwrap();
+            let mut request = vec![0; 1024];
+            let n = sock.read(&mut request).unwrap();
+            sock.write_all(status.as_bytes()).unwrap();
+            String::from_utf8_lossy(&request[..n]).into_owned()
+        });
+
+        let mut core = Core::new().unwrap();
+        let timeouts = (Duration::from_secs(5), Duration::from_secs(5));
+        let post = post_http(addr, "example", "/in?db=a", "text/plain", "a 1\n", timeouts,
+                             &core.handle());
+        let res = core.run(post).map_err(|e| e.kind());
+        (res, server.join().unwrap())
+    }
+
+    #[test]
+    fn posts_over_http() {
+        let (res, request) = post_to("HTTP/1.1 204 No Content\r\n\r\n");
+        assert_eq!(res, Ok(()));
+        assert_eq!(request,
+                   "POST /in?db=a HTTP/1.1\r\nHost: example\r\nContent-Type: text/plain\r\n\
+                    Content-Length: 4\r\nConnection: close\r\n\r\na 1\n");
+
+        let (res, _) = post_to("HTTP/1.1 400 Bad Request\r\n\r\n");
+        assert_eq!(res, Err(ErrorKind::Other));
+    }
 }
@@ -2,55 +2,469 @@
 //! corresponding backend.
 #![deny(missing_docs)]
 
-use std::collections::{hash_map, HashMap, HashSet};
+use std::collections::{btree_map, hash_map, BTreeMap, HashMap, HashSet};
+use std::collections::hash_map::DefaultHasher;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::mem;
+use std::net::IpAddr;
 use std::rc::Rc;
+use std::slice;
+use std::time::Duration;
 
-use parse::{Metric, MetricType};
+use error::Error;
+
+use hll::HyperLogLog;
+
+use parse::{Event, Line, Metric, MetricType, ServiceCheck, Tag};
+
+use sketch::{DDSketch, DEFAULT_MAX_BINS, DEFAULT_RELATIVE_ACCURACY};
+
+use snapshot::FlushSnapshot;
+
+/// `MetricKey` identifies a single aggregate in the cache. It is made up of the metric name and
+/// the canonical form of its tags, so the same tags sent in a different order or repeated still
+/// land in the same bucket.
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub struct MetricKey {
+    name: Rc<str>,
+    tags: Rc<Vec<Tag>>,
+}
+
+impl MetricKey {
+    /// Create a new key from a metric name and its tags. The tags are sorted and de-duplicated.
+    pub fn new(name: Rc<str>, tags: &[Tag]) -> MetricKey {
+        MetricKey {
+            name,
+            tags: Rc::new(canonical_tags(tags.to_vec())),
+        }
+    }
+
+    /// Return the name of the metric.
+    #[inline]
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Return the canonical tags of the metric.
+    #[inline]
+    pub fn tags(&self) -> &[Tag] {
+        &self.tags
+    }
+}
+
+// Sort and de-duplicate tags so that their order does not matter.
+fn canonical_tags(mut tags: Vec<Tag>) -> Vec<Tag> {
+    tags.sort();
+    tags.dedup();
+    tags
+}
+
+// `Interner` keeps a single copy of every metric name and tag set in the cache. A metric is only
+// copied out of its packet the first time it is seen, and later lookups do not allocate.
+#[derive(Debug, Default)]
+struct Interner {
+    names: HashSet<Rc<str>>,
+    tag_sets: HashMap<Box<str>, Rc<Vec<Tag>>>,
+    no_tags: Rc<Vec<Tag>>,
+}
+
+impl Interner {
+    // Return the key for a metric, interning its name and tags if they are new. Tag sets are
+    // looked up exactly as they were sent, so the same tags in another order are interned twice
+    // but still make equal keys.
+    fn key(&mut self, metric: &Metric) -> MetricKey {
+        let name = match self.names.get(metric.name) {
+            Some(name) => name.clone(),
+            None => {
+                let name: Rc<str> = Rc::from(metric.name);
+                self.names.insert(name.clone());
+                name
+            }
+        };
+
+        let tags = if metric.raw_tags.is_empty() {
+            self.no_tags.clone()
+        } else {
+            match self.tag_sets.get(metric.raw_tags) {
+                Some(tags) => tags.clone(),
+                None => {
+                    let tags = Rc::new(canonical_tags(metric.tags().collect()));
+                    self.tag_sets.insert(Box::from(metric.raw_tags), tags.clone());
+                    tags
+                }
+            }
+        };
+
+        MetricKey { name, tags }
+    }
+
+    // Forget the names and tags that only the interner still refers to.
+    fn prune(&mut self) {
+        self.names.retain(|name| Rc::strong_count(name) > 1);
+        self.tag_sets.retain(|_, tags| Rc::strong_count(tags) > 1);
+    }
+}
+
+/// `TimerStats` holds the statistics derived from a single timer, in the order they were
+/// computed. Each entry is the name of the statistic and its value.
+pub type TimerStats = Vec<(String, f64)>;
+
+/// `SetValues` holds the members of a single set. Most sets keep every distinct member, while
+/// sets that are expected to be very large keep a HyperLogLog sketch of them instead.
+#[derive(Clone, Debug)]
+pub enum SetValues {
+    /// Every distinct member, as a 64 bit hash.
+    Exact(HashSet<u64>),
+
+    /// An approximation of the members with bounded memory.
+    Approximate(HyperLogLog),
+}
+
+impl SetValues {
+    /// Add the hash of a member to the set.
+    pub fn insert(&mut self, hash: u64) {
+        match *self {
+            SetValues::Exact(ref mut members) => {
+                members.insert(hash);
+            }
+            SetValues::Approximate(ref mut hll) => hll.insert(hash),
+        }
+    }
+
+    /// Return the number of distinct members. This is an estimate for approximate sets.
+    pub fn cardinality(&self) -> f64 {
+        match *self {
+            SetValues::Exact(ref members) => members.len() as f64,
+            SetValues::Approximate(ref hll) => hll.cardinality(),
+        }
+    }
+
+    /// Remove every member from the set.
+    pub fn clear(&mut self) {
+        match *self {
+            SetValues::Exact(ref mut members) => members.clear(),
+            SetValues::Approximate(ref mut hll) => hll.clear(),
+        }
+    }
+}
+
+/// `GaugeRetention` decides how long a gauge is kept once it stops being updated.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum GaugeRetention {
+    /// Keep reporting the last value of a gauge forever.
+    Forever,
+
+    /// Keep reporting a gauge for this many flushes without an update, then drop it.
+    Idle(u32),
+
+    /// Drop every gauge after each flush.
+    Clear,
+}
+
+/// `HistogramBins` counts the values of every timer whose name contains `pattern` into bins.
+/// Each bin counts the values greater than the previous bin and at most its own upper bound, so a
+/// last bin of infinity catches everything left over.
+#[derive(Clone, Debug, PartialEq)]
+pub struct HistogramBins {
+    /// The substring a timer name must contain. An empty pattern matches every timer.
+    pub pattern: String,
+
+    /// The upper bound of each bin, in ascending order.
+    pub bins: Vec<f64>,
+}
+
+/// The most sources whose bad lines are counted on their own during one flush interval. Bad lines
+/// from any further sources are still counted by reason, which keeps a flood of spoofed UDP
+/// addresses from growing the cache without bound.
+pub const MAX_BAD_SOURCES: usize = 1024;
+
+/// `Source` identifies the client a line was received from.
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub enum Source {
+    /// A client that sent the line over UDP or TCP.
+    Ip(IpAddr),
+
+    /// A client that sent the line over the Unix socket, which does not tell clients apart.
+    Unix,
+}
+
+impl fmt::Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Source::Ip(ref ip) => ip.fmt(f),
+            Source::Unix => f.write_str("unix"),
+        }
+    }
+}
+
+/// `BackendStats` describes the flushes a backend finished since the last flush.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct BackendStats {
+    /// The number of flushes the backend finished.
+    pub flushes: u64,
+
+    /// The number of those flushes that failed.
+    pub errors: u64,
+
+    /// The longest any of those flushes took, from the moment the backend was handed the flush
+    /// until it finished.
+    pub max_latency: Duration,
+}
+
+/// Return the tag that backends attach to the statistics kept about a backend.
+pub fn backend_tag(backend: &str) -> Tag {
+    Tag {
+        name: String::from("backend"),
+        value: Some(String::from(backend)),
+    }
+}
+
+/// Return the tag that backends attach to the bad metrics counted for a source.
+pub fn source_tag(source: &Source) -> Tag {
+    Tag {
+        name: String::from("source"),
+        value: Some(source.to_string()),
+    }
+}
+
+/// `CacheConfig` holds the options that change how metrics are aggregated.
+#[derive(Clone, Debug)]
+pub struct CacheConfig {
+    /// The percentiles computed for every timer. Each one produces an `upper_N`, `mean_N` and
+    /// `sum_N` statistic where `N` is the percentile with any `.` replaced by `_`.
+    pub percentiles: Vec<f64>,
+
+    /// The time between flushes in seconds, used to turn counters into per second rates.
+    pub flush_interval: f64,
+
+    /// How long gauges are kept once they stop being updated.
+    pub gauge_retention: GaugeRetention,
+
+    /// Drop counters after each flush. Otherwise they are reported as zero until updated.
+    pub delete_idle_counters: bool,
+
+    /// Drop timers, histograms and distributions after each flush. Otherwise they are reported
+    /// with a count of zero.
+    pub delete_idle_timers: bool,
+
+    /// Drop sets after each flush. Otherwise they are reported as empty.
+    pub delete_idle_sets: bool,
+
+    /// Sets whose name starts with one of these prefixes are approximated with a HyperLogLog.
+    pub hll_prefixes: Vec<String>,
+
+    /// The precision of the HyperLogLog used by approximate sets.
+    pub hll_precision: u8,
+
+    /// The relative accuracy of the sketches that hold distributions and sketched timers.
+    pub sketch_accuracy: f64,
+
+    /// Timers whose name starts with one of these prefixes are kept in a sketch instead of
+    /// storing every value.
+    pub timer_sketch_prefixes: Vec<String>,
+
+    /// The bins counted for timers. Only the first entry whose pattern matches is used.
+    pub histograms: Vec<HistogramBins>,
+
+    /// How many bad lines from each source are logged during a flush interval. Zero turns the
+    /// logging off.
+    pub bad_line_log_limit: u64,
+}
+
+impl CacheConfig {
+    // The histogram bins counted for a timer, if its name matches a configured pattern.
+    fn histogram_bins(&self, name: &str) -> Option<&HistogramBins> {
+        self.histograms.iter().find(|h| name.contains(&h.pattern))
+    }
+}
+
+impl Default for CacheConfig {
+    fn default() -> CacheConfig {
+        CacheConfig {
+            percentiles: vec![95.0],
+            flush_interval: 10.0,
+            gauge_retention: GaugeRetention::Forever,
+            delete_idle_counters: true,
+            delete_idle_timers: true,
+            delete_idle_sets: true,
+            hll_prefixes: Vec::new(),
+            hll_precision: 14,
+            sketch_accuracy: DEFAULT_RELATIVE_ACCURACY,
+            timer_sketch_prefixes: Vec::new(),
+            histograms: Vec::new(),
+            bad_line_log_limit: 0,
+        }
+    }
+}
 
 /// `CapellaCache` is the bucketing mechanism used by capella to buffer metrics before sending to
 /// the backend.
 #[derive(Debug, Default)]
 pub struct CapellaCache {
-    counters: HashMap<Rc<String>, f64>,
-    gauges: HashMap<Rc<String>, f64>,
-    timers: HashMap<Rc<String>, Vec<f64>>,
-    sets: HashMap<Rc<String>, HashSet<i64>>,
-    timer_data: HashMap<String, f64>,
+    config: CacheConfig,
+    interner: Interner,
+    counters: HashMap<MetricKey, f64>,
+    gauges: HashMap<MetricKey, f64>,
+    gauge_idle_flushes: HashMap<MetricKey, u32>,
+    timers: HashMap<MetricKey, Vec<f64>>,
+    timer_counters: HashMap<MetricKey, f64>,
+    timer_sketches: HashMap<MetricKey, DDSketch>,
+    timer_sketch_bins: HashMap<MetricKey, Vec<f64>>,
+    sets: HashMap<MetricKey, SetValues>,
+    distributions: HashMap<MetricKey, DDSketch>,
+    timer_data: HashMap<MetricKey, TimerStats>,
+    events: Vec<Event>,
+    service_checks: Vec<ServiceCheck>,
     metrics_seen: u64,
     bad_metrics: u64,
+    bad_metric_reasons: BTreeMap<&'static str, u64>,
+    bad_sources: BTreeMap<Source, u64>,
+    backend_stats: BTreeMap<&'static str, BackendStats>,
 }
 
 impl CapellaCache {
+    /// Create a new cache that aggregates using the given configuration.
+    pub fn new(config: CacheConfig) -> CapellaCache {
+        CapellaCache { config, ..CapellaCache::default() }
+    }
+
+    /// Add everything parsed from a single line to the cache.
+    pub fn add_line(&mut self, line: &Line) {
+        match *line {
+            Line::Metrics(ref metrics) => {
+                for m in metrics {
+                    self.add_metric(m);
+                }
+            }
+            Line::Event(ref event) => self.add_event(event.clone()),
+            Line::ServiceCheck(ref check) => self.add_service_check(check.clone()),
+        }
+    }
+
+    /// Keep an event until the next flush.
+    pub fn add_event(&mut self, event: Event) {
+        self.metric_count_increase();
+        self.events.push(event);
+    }
+
+    /// Keep a service check until the next flush.
+    pub fn add_service_check(&mut self, check: ServiceCheck) {
+        self.metric_count_increase();
+        self.service_checks.push(check);
+    }
+
     /// This function will add a `Metric` to the cache.
     pub fn add_metric(&mut self, metric: &Metric) {
         self.metric_count_increase();
+        let key = self.interner.key(metric);
+        // A metric sampled at a rate of 0.1 stands in for ten metrics that were not sent.
+        let sample_weight = 1.0 / metric.sample_rate.unwrap_or(1.0);
 
         match metric.metric_type {
             MetricType::Counter => {
-                let c = self.counters.entry(metric.name.clone()).or_insert(0.0);
-                *c += metric.value * metric.sample_rate.unwrap_or(1.0);
+                let c = self.counters.entry(key).or_insert(0.0);
+                *c += metric.value * sample_weight;
             }
             MetricType::Gauge => {
-                self.gauges.insert(metric.name.clone(), metric.value);
+                self.gauge_idle_flushes.insert(key.clone(), 0);
+
+                // A signed value adjusts the gauge, starting from zero if it has not been seen.
+                if metric.explicit_sign {
+                    *self.gauges.entry(key).or_insert(0.0) += metric.value;
+                } else {
+                    self.gauges.insert(key, metric.value);
+                }
             }
-            MetricType::Timer => {
-                let values = self.timers.entry(metric.name.clone()).or_insert(Vec::new());
-                values.push(metric.value * metric.sample_rate.unwrap_or(1.0));
+            MetricType::Timer | MetricType::Histogram => {
+                let prefixes = &self.config.timer_sketch_prefixes;
+                if prefixes.iter().any(|p| metric.name.starts_with(p.as_str())) {
+                    let accuracy = self.config.sketch_accuracy;
+                    self.timer_sketches
+                        .entry(key.clone())
+                        .or_insert_with(|| DDSketch::new(accuracy, DEFAULT_MAX_BINS))
+                        .insert_weighted(metric.value, sample_weight);
+
+                    // Histogram bins count the values received, like those of an exact timer, so
+                    // they are counted here rather than read back from the weighted sketch.
+                    if let Some(histogram) = self.config.histogram_bins(metric.name) {
+                        let bins = self.timer_sketch_bins
+                            .entry(key)
+                            .or_insert_with(|| vec![0.0; histogram.bins.len()]);
+                        if let Some(i) = histogram.bins.iter().position(|b| metric.value <= *b) {
+                            bins[i] += 1.0;
+                        }
+                    }
+                    return;
+                }
+
+                // Sampling does not change the measured time, only how many times were measured.
+                *self.timer_counters.entry(key.clone()).or_insert(0.0) += sample_weight;
+                let values = self.timers.entry(key).or_default();
+                values.push(metric.value);
             }
             MetricType::Set => {
-                let values = self.sets.entry(metric.name.clone()).or_insert(HashSet::new());
-                values.insert(metric.value.round() as i64);
+                // Members are kept as 64 bit hashes so that long strings such as session tokens
+                // cost the same as a number.
+                let mut hasher = DefaultHasher::new();
+                metric.member.unwrap_or("").hash(&mut hasher);
+                let config = &self.config;
+                let values = self.sets.entry(key).or_insert_with(|| {
+                    if config.hll_prefixes.iter().any(|p| metric.name.starts_with(p.as_str())) {
+                        SetValues::Approximate(HyperLogLog::new(config.hll_precision))
+                    } else {
+                        SetValues::Exact(HashSet::new())
+                    }
+                });
+                values.insert(hasher.finish());
+            }
+            MetricType::Distribution => {
+                let accuracy = self.config.sketch_accuracy;
+                self.distributions
+                    .entry(key)
+                    .or_insert_with(|| DDSketch::new(accuracy, DEFAULT_MAX_BINS))
+                    .insert_weighted(metric.value, sample_weight);
             }
         }
     }
 
-    /// Increase the count of bad messages that could not be parsed.
+    /// Increase the count of bad messages that could not be parsed, along with the count for the
+    /// reason the error gives.
     #[inline]
-    pub fn bad_metric_count_increase(&mut self) {
+    pub fn bad_metric_count_increase(&mut self, error: &Error) {
         self.bad_metrics += 1;
+        *self.bad_metric_reasons.entry(error.reason()).or_insert(0) += 1;
         self.metric_count_increase();
     }
 
+    /// Count a bad line against the source that sent it as well as by reason. This returns whether
+    /// the line is within the number of bad lines from the source that should be logged during
+    /// this flush interval.
+    pub fn bad_line_from(&mut self, source: Source, error: &Error) -> bool {
+        self.bad_metric_count_increase(error);
+
+        if self.bad_sources.len() >= MAX_BAD_SOURCES && !self.bad_sources.contains_key(&source) {
+            return false;
+        }
+        let count = self.bad_sources.entry(source).or_insert(0);
+        *count += 1;
+        *count <= self.config.bad_line_log_limit
+    }
+
+    /// Record that a backend finished a flush, and whether it failed. Flushes finish after the
+    /// cache they were handed has been reset, so they are reported with the next flush.
+    pub fn backend_flush_finished(&mut self,
+                                  backend: &'static str,
+                                  latency: Duration,
+                                  failed: bool) {
+        let stats = self.backend_stats.entry(backend).or_default();
+        stats.flushes += 1;
+        if failed {
+            stats.errors += 1;
+        }
+        stats.max_latency = stats.max_latency.max(latency);
+    }
+
     /// Increase the unique metric count.
     #[inline]
     pub fn metric_count_increase(&mut self) {
@@ -69,74 +483,311 @@ impl CapellaCache {
         self.bad_metrics as f64
     }
 
+    /// Return an iterator over the number of failed metrics seen for each reason, ordered by the
+    /// reason. Only reasons that were seen since the last flush are included.
+    pub fn bad_metrics_iter(&self) -> btree_map::Iter<'_, &'static str, u64> {
+        self.bad_metric_reasons.iter()
+    }
+
+    /// Return an iterator over the number of failed metrics seen from each source, ordered by the
+    /// source. Only sources that sent a bad line since the last flush are included.
+    pub fn bad_sources_iter(&self) -> btree_map::Iter<'_, Source, u64> {
+        self.bad_sources.iter()
+    }
+
+    /// Return an iterator over the flushes each backend finished since the last flush, ordered by
+    /// the name of the backend.
+    pub fn backend_stats_iter(&self) -> btree_map::Iter<'_, &'static str, BackendStats> {
+        self.backend_stats.iter()
+    }
+
     /// Return an iterator over the counters.
-    pub fn counters_iter(&self) -> hash_map::Iter<Rc<String>, f64> {
+    pub fn counters_iter(&self) -> hash_map::Iter<'_, MetricKey, f64> {
         self.counters.iter()
     }
 
+    /// Return an iterator over the per second rate of each counter over the flush interval.
+    pub fn counter_rates_iter(&self) -> impl Iterator<Item = (&MetricKey, f64)> {
+        let interval = self.config.flush_interval;
+        self.counters.iter().map(move |(k, v)| (k, v / interval))
+    }
+
     /// Return an iterator over the gauges.
-    pub fn gauges_iter(&self) -> hash_map::Iter<Rc<String>, f64> {
+    pub fn gauges_iter(&self) -> hash_map::Iter<'_, MetricKey, f64> {
         self.gauges.iter()
     }
 
     /// Return an iterator over the sets.
-    pub fn sets_iter(&self) -> hash_map::Iter<Rc<String>, HashSet<i64>> {
+    pub fn sets_iter(&self) -> hash_map::Iter<'_, MetricKey, SetValues> {
         self.sets.iter()
     }
 
+    /// Return an iterator over the distribution sketches, which can be merged with sketches from
+    /// other aggregators.
+    pub fn distributions_iter(&self) -> hash_map::Iter<'_, MetricKey, DDSketch> {
+        self.distributions.iter()
+    }
+
+    /// Return an iterator over the events received since the last flush, in the order they arrived.
+    pub fn events_iter(&self) -> slice::Iter<'_, Event> {
+        self.events.iter()
+    }
+
+    /// Return an iterator over the service checks received since the last flush, in the order
+    /// they arrived.
+    pub fn service_checks_iter(&self) -> slice::Iter<'_, ServiceCheck> {
+        self.service_checks.iter()
+    }
+
     /// Return an iterator over the timer data.
-    pub fn timer_data_iter(&self) -> hash_map::Iter<String, f64> {
+    pub fn timer_data_iter(&self) -> hash_map::Iter<'_, MetricKey, TimerStats> {
         self.timer_data.iter()
     }
 
-    /// Clear the counter and timer data.
+    /// Prepare the cache for the next flush interval. Counters, timers and sets are either dropped
+    /// or zeroed and gauges are expired according to the configuration.
     pub fn reset(&mut self) {
-        self.counters.clear();
-        self.sets.clear();
-        self.timers.clear();
+        if self.config.delete_idle_counters {
+            self.counters.clear();
+        } else {
+            for v in self.counters.values_mut() {
+                *v = 0.0;
+            }
+        }
+
+        if self.config.delete_idle_sets {
+            self.sets.clear();
+        } else {
+            for v in self.sets.values_mut() {
+                v.clear();
+            }
+        }
+
+        if self.config.delete_idle_timers {
+            self.timers.clear();
+            self.timer_counters.clear();
+        } else {
+            for v in self.timers.values_mut() {
+                v.clear();
+            }
+            for v in self.timer_counters.values_mut() {
+                *v = 0.0;
+            }
+        }
+
+        if self.config.delete_idle_timers {
+            self.timer_sketches.clear();
+            self.timer_sketch_bins.clear();
+            self.distributions.clear();
+        } else {
+            for v in self.timer_sketches.values_mut().chain(self.distributions.values_mut()) {
+                v.clear();
+            }
+            for v in self.timer_sketch_bins.values_mut() {
+                for count in v.iter_mut() {
+                    *count = 0.0;
+                }
+            }
+        }
+
+        match self.config.gauge_retention {
+            GaugeRetention::Forever => {}
+            GaugeRetention::Clear => {
+                self.gauges.clear();
+                self.gauge_idle_flushes.clear();
+            }
+            GaugeRetention::Idle(max_idle) => {
+                let gauges = &mut self.gauges;
+                self.gauge_idle_flushes.retain(|k, idle| {
+                    *idle += 1;
+                    if *idle > max_idle {
+                        gauges.remove(k);
+                        return false;
+                    }
+                    true
+                });
+            }
+        }
+
         self.timer_data.clear();
+        self.events.clear();
+        self.service_checks.clear();
+        self.interner.prune();
         self.metrics_seen = 0;
         self.bad_metrics = 0;
+        self.bad_metric_reasons.clear();
+        self.bad_sources.clear();
+        self.backend_stats.clear();
     }
 
-    /// Make timer data statistics.
+    /// Make the timer statistics, move everything the backends report into a snapshot taken at
+    /// the given Unix time and prepare the cache for the next flush interval.
+    pub fn flush(&mut self, timestamp: i64) -> FlushSnapshot {
+        self.make_timer_stats();
+        let snapshot = FlushSnapshot {
+            timestamp,
+            flush_interval: self.config.flush_interval,
+            counters: self.counters.clone(),
+            gauges: self.gauges.clone(),
+            sets: self.sets.iter().map(|(k, v)| (k.clone(), v.cardinality())).collect(),
+            timer_data: mem::take(&mut self.timer_data),
+            distributions: self.distributions.clone(),
+            events: mem::take(&mut self.events),
+            service_checks: mem::take(&mut self.service_checks),
+            metrics_seen: self.metrics_seen,
+            bad_metrics: self.bad_metrics,
+            bad_metric_reasons: mem::take(&mut self.bad_metric_reasons),
+            bad_sources: mem::take(&mut self.bad_sources),
+            backend_stats: mem::take(&mut self.backend_stats),
+        };
+        self.reset();
+
+        snapshot
+    }
+
+    /// Make the statistics for timers, histograms and distributions.
     pub fn make_timer_stats(&mut self) {
-        let mut timer_data: HashMap<String, f64> = HashMap::new();
+        let mut timer_data = HashMap::new();
+
+        for (key, times) in &mut self.timers {
+            // A timer kept from an earlier interval without new values only reports its count,
+            // just like StatsD.
+            if times.is_empty() {
+                timer_data.insert(key.clone(), idle_timer_stats());
+                continue;
+            }
 
-        for (metric, times) in &mut self.timers {
             // Sort the metrics for calculating statistics.
             times.sort_by(|a, b| a.partial_cmp(b).unwrap());
 
+            // The count accounts for sampling, while the other statistics only use the values
+            // that were actually received.
+            let sampled_count = self.timer_counters.get(key).cloned().unwrap_or(0.0);
+            let count_ps = sampled_count / self.config.flush_interval;
+
             let count = times.len() as f64;
             let sum: f64 = times.iter().sum();
             let average = sum / count;
             let std_dev = get_std_dev(times, average, count);
-            let median = get_percentile(times, count, 0.5);
-            let upper_ninety_five = get_percentile(times, count, 0.95);
-
-            timer_data.insert(String::from(&*metric.clone().as_str()) + ".min", times[0]);
-            timer_data.insert(String::from(&*metric.clone().as_str()) + ".max",
-                              times[times.len() - 1]);
-            timer_data.insert(String::from(&*metric.clone().as_str()) + ".count", count);
-            timer_data.insert(String::from(&*metric.clone().as_str()) + ".average",
-                              average);
-            timer_data.insert(String::from(&*metric.clone().as_str()) + ".std_dev",
-                              std_dev);
-            timer_data.insert(String::from(&*metric.clone().as_str()) + ".median", median);
-            timer_data.insert(String::from(&*metric.clone().as_str()) + ".upper_95",
-                              upper_ninety_five);
+            let median = get_median(times);
+
+            let mut stats = vec![(String::from("min"), times[0]),
+                                 (String::from("max"), times[times.len() - 1]),
+                                 (String::from("count"), sampled_count),
+                                 (String::from("count_ps"), count_ps),
+                                 (String::from("average"), average),
+                                 (String::from("std_dev"), std_dev),
+                                 (String::from("median"), median)];
+
+            for percentile in &self.config.percentiles {
+                let (upper, mean, sum) = match get_percentile(times, *percentile) {
+                    Some(p) => p,
+                    None => continue,
+                };
+                let suffix = percentile_suffix(*percentile);
+                stats.push((format!("upper_{}", suffix), upper));
+                stats.push((format!("mean_{}", suffix), mean));
+                stats.push((format!("sum_{}", suffix), sum));
+            }
+
+            if let Some(histogram) = self.config.histogram_bins(key.name()) {
+                let mut lower = 0;
+                for bin in &histogram.bins {
+                    let upper = times.partition_point(|t| t <= bin);
+                    stats.push((histogram_bin_name(*bin), (upper - lower) as f64));
+                    lower = upper;
+                }
+            }
+
+            timer_data.insert(key.clone(), stats);
+        }
+
+        for (key, sketch) in &self.timer_sketches {
+            let mut stats = self.sketch_stats(sketch);
+            let histogram = self.config.histogram_bins(key.name()).filter(|_| !sketch.is_empty());
+            if let (Some(histogram), Some(counts)) = (histogram, self.timer_sketch_bins.get(key)) {
+                for (bin, count) in histogram.bins.iter().zip(counts) {
+                    stats.push((histogram_bin_name(*bin), *count));
+                }
+            }
+
+            timer_data.insert(key.clone(), stats);
+        }
+
+        for (key, sketch) in &self.distributions {
+            timer_data.insert(key.clone(), self.sketch_stats(sketch));
         }
 
         self.timer_data = timer_data;
     }
+
+    // A sketch only knows its quantiles approximately, so sketches report the upper bound of each
+    // percentile but not its mean or sum.
+    fn sketch_stats(&self, sketch: &DDSketch) -> TimerStats {
+        let (min, max, std_dev) = match (sketch.min(), sketch.max(), sketch.std_dev()) {
+            (Some(min), Some(max), Some(std_dev)) => (min, max, std_dev),
+            _ => return idle_timer_stats(),
+        };
+
+        let count = sketch.count();
+        let mut stats = vec![(String::from("min"), min),
+                             (String::from("max"), max),
+                             (String::from("count"), count),
+                             (String::from("count_ps"), count / self.config.flush_interval),
+                             (String::from("average"), sketch.sum() / count),
+                             (String::from("std_dev"), std_dev),
+                             (String::from("median"), sketch.quantile(0.5).unwrap())];
+
+        for percentile in &self.config.percentiles {
+            let upper = sketch.quantile(percentile / 100.0).unwrap();
+            stats.push((format!("upper_{}", percentile_suffix(*percentile)), upper));
+        }
+
+        stats
+    }
+}
+
+// The statistics for a timer that was kept from an earlier interval but saw no new values.
+fn idle_timer_stats() -> TimerStats {
+    vec![(String::from("count"), 0.0), (String::from("count_ps"), 0.0)]
+}
+
+// The name of a percentile as it appears in a statistic, such as `99_9` for the 99.9th.
+fn percentile_suffix(percentile: f64) -> String {
+    percentile.to_string().replace('.', "_")
+}
+
+// The name of the statistic that holds the count of a histogram bin, such as `histogram.bin_0_5`.
+fn histogram_bin_name(bin: f64) -> String {
+    format!("histogram.bin_{}", bin.to_string().replace('.', "_"))
 }
 
-fn get_percentile(values: &[f64], count: f64, percent: f64) -> f64 {
-    let index = (count * percent) as usize;
-    if values.len() % 2 == 0 {
-        return (values[index - 1] + values[index]) / 2.0;
+// The median of a sorted, non-empty slice. Even lengths average the two middle values.
+fn get_median(values: &[f64]) -> f64 {
+    let mid = values.len() / 2;
+    if values.len().is_multiple_of(2) {
+        return (values[mid - 1] + values[mid]) / 2.0;
     }
-    values[index]
+    values[mid]
+}
+
+// Return the upper bound, mean and sum of the values that fall within the given percentile of a
+// sorted, non-empty slice. This follows StatsD: the number of values kept is rounded, a single
+// value is always kept, and a percentile that keeps no values is skipped.
+fn get_percentile(values: &[f64], percentile: f64) -> Option<(f64, f64, f64)> {
+    let count = values.len();
+    let in_threshold = if count == 1 {
+        1
+    } else {
+        ((percentile / 100.0) * count as f64).round() as usize
+    };
+    if in_threshold == 0 {
+        return None;
+    }
+
+    let kept = &values[..in_threshold];
+    let sum: f64 = kept.iter().sum();
+    Some((kept[in_threshold - 1], sum / in_threshold as f64, sum))
 }
 
 fn get_std_dev(values: &[f64], average: f64, count: f64) -> f64 {
@@ -145,23 +796,44 @@ fn get_std_dev(values: &[f64], average: f64, count: f64) -> f64 {
 
 #[cfg(test)]
 mod tests {
+    use std::net::IpAddr;
     use std::rc::Rc;
 
-    use super::CapellaCache;
-    use parse::{Metric, MetricType};
+    use super::{get_median, get_percentile, CacheConfig, CapellaCache, GaugeRetention,
+                HistogramBins, MetricKey, SetValues, Source};
+    use error::Error;
+    use parse::{parse_line, Metric, MetricType, Tag};
 
     const EPSILON: f64 = 1e-32;
 
     // Create a new timer metric.
-    fn make_timer_metric(name: &str, value: f64) -> Metric {
+    fn make_timer_metric(name: &str, value: f64) -> Metric<'_> {
         Metric {
-            name: Rc::new(String::from(name)),
-            value: value,
+            name,
+            value,
+            member: None,
             metric_type: MetricType::Timer,
             sample_rate: None,
+            raw_tags: "",
+            explicit_sign: false,
+        }
+    }
+
+    // Create a new tag with a value.
+    fn make_tag(name: &str, value: &str) -> Tag {
+        Tag {
+            name: String::from(name),
+            value: Some(String::from(value)),
         }
     }
 
+    // Look up a single statistic for an untagged timer.
+    fn timer_stat(cache: &CapellaCache, name: &str, stat: &str) -> f64 {
+        let key = MetricKey::new(Rc::from(name), &[]);
+        let stats = cache.timer_data.get(&key).unwrap();
+        stats.iter().find(|s| s.0 == stat).unwrap().1
+    }
+
     #[test]
     fn timer_data_generation() {
         let mut timers = Vec::new();
@@ -180,12 +852,495 @@ mod tests {
         }
         cache.make_timer_stats();
 
-        assert!((cache.timer_data.get("test.min").unwrap() - 1.0).abs() < EPSILON);
-        assert!((cache.timer_data.get("test.max").unwrap() - 5.0).abs() < EPSILON);
-        assert!((cache.timer_data.get("test.count").unwrap() - 5.0).abs() < EPSILON);
-        assert!((cache.timer_data.get("test.average").unwrap() - 3.0).abs() < EPSILON);
-        assert!((cache.timer_data.get("test.std_dev").unwrap() - sqrt_two).abs() < EPSILON);
-        assert!((cache.timer_data.get("test.median").unwrap() - 3.0).abs() < EPSILON);
-        assert!((cache.timer_data.get("test.upper_95").unwrap() - 5.0).abs() < EPSILON);
+        assert!((timer_stat(&cache, "test", "min") - 1.0).abs() < EPSILON);
+        assert!((timer_stat(&cache, "test", "max") - 5.0).abs() < EPSILON);
+        assert!((timer_stat(&cache, "test", "count") - 5.0).abs() < EPSILON);
+        assert!((timer_stat(&cache, "test", "average") - 3.0).abs() < EPSILON);
+        assert!((timer_stat(&cache, "test", "std_dev") - sqrt_two).abs() < EPSILON);
+        assert!((timer_stat(&cache, "test", "median") - 3.0).abs() < EPSILON);
+        assert!((timer_stat(&cache, "test", "upper_95") - 5.0).abs() < EPSILON);
+    }
+
+    #[test]
+    fn configured_percentiles() {
+        let config = CacheConfig {
+            percentiles: vec![90.0, 80.0, 99.9],
+            ..CacheConfig::default()
+        };
+        let mut cache = CapellaCache::new(config);
+
+        for v in &[300.0, 100.0, 200.0] {
+            cache.add_metric(&make_timer_metric("test", *v));
+        }
+        cache.make_timer_stats();
+
+        // These match the StatsD reference outputs for the same timer.
+        assert!((timer_stat(&cache, "test", "upper_90") - 300.0).abs() < EPSILON);
+        assert!((timer_stat(&cache, "test", "mean_90") - 200.0).abs() < EPSILON);
+        assert!((timer_stat(&cache, "test", "sum_90") - 600.0).abs() < EPSILON);
+        assert!((timer_stat(&cache, "test", "upper_80") - 200.0).abs() < EPSILON);
+        assert!((timer_stat(&cache, "test", "mean_80") - 150.0).abs() < EPSILON);
+        assert!((timer_stat(&cache, "test", "sum_80") - 300.0).abs() < EPSILON);
+        assert!((timer_stat(&cache, "test", "upper_99_9") - 300.0).abs() < EPSILON);
+    }
+
+    // Add a metric with a sample rate to the cache.
+    fn add_sampled(cache: &mut CapellaCache, metric_type: MetricType, value: f64, rate: f64) {
+        let mut m = Metric::new();
+        m.name = "test";
+        m.value = value;
+        m.metric_type = metric_type;
+        m.sample_rate = Some(rate);
+        cache.add_metric(&m);
+    }
+
+    #[test]
+    fn gauge_deltas() {
+        let mut cache = CapellaCache::default();
+        let key = MetricKey::new(Rc::from("test"), &[]);
+        let mut gauge = Metric::new();
+        gauge.name = "test";
+        gauge.metric_type = MetricType::Gauge;
+
+        // A delta without a previous value starts from zero.
+        gauge.value = -1.0;
+        gauge.explicit_sign = true;
+        cache.add_metric(&gauge);
+        assert!((cache.gauges.get(&key).unwrap() + 1.0).abs() < EPSILON);
+
+        // A bare value sets the gauge and later deltas apply to it, even across a flush.
+        gauge.value = 10.0;
+        gauge.explicit_sign = false;
+        cache.add_metric(&gauge);
+        cache.reset();
+
+        gauge.value = 4.0;
+        gauge.explicit_sign = true;
+        cache.add_metric(&gauge);
+        gauge.value = -1.0;
+        cache.add_metric(&gauge);
+        assert!((cache.gauges.get(&key).unwrap() - 13.0).abs() < EPSILON);
+    }
+
+    #[test]
+    fn string_set_members() {
+        let mut cache = CapellaCache::default();
+        let key = MetricKey::new(Rc::from("test"), &[]);
+        let mut set = Metric::new();
+        set.name = "test";
+        set.metric_type = MetricType::Set;
+
+        for member in &["alice", "bob", "alice", "11", "11.4"] {
+            set.member = Some(*member);
+            cache.add_metric(&set);
+        }
+
+        assert_eq!(cache.sets.get(&key).unwrap().cardinality(), 4.0);
+    }
+
+    #[test]
+    fn approximate_sets_by_prefix() {
+        let config = CacheConfig {
+            hll_prefixes: vec![String::from("users.")],
+            ..CacheConfig::default()
+        };
+        let mut cache = CapellaCache::new(config);
+        for i in 0..10_000 {
+            let member = i.to_string();
+            let mut set = Metric::new();
+            set.metric_type = MetricType::Set;
+            set.member = Some(&member);
+            set.name = "users.unique";
+            cache.add_metric(&set);
+            set.name = "hosts";
+            cache.add_metric(&set);
+        }
+
+        let users = MetricKey::new(Rc::from("users.unique"), &[]);
+        let hosts = MetricKey::new(Rc::from("hosts"), &[]);
+        match *cache.sets.get(&users).unwrap() {
+            SetValues::Approximate(ref hll) => {
+                assert!((hll.cardinality() - 10_000.0).abs() < 10_000.0 * 0.03)
+            }
+            SetValues::Exact(_) => panic!("users should be approximated"),
+        }
+        assert_eq!(cache.sets.get(&hosts).unwrap().cardinality(), 10_000.0);
+    }
+
+    #[test]
+    fn gauge_retention() {
+        let key = MetricKey::new(Rc::from("test"), &[]);
+        let mut gauge = Metric::new();
+        gauge.name = "test";
+        gauge.metric_type = MetricType::Gauge;
+        gauge.value = 1.0;
+
+        let mut forever = CapellaCache::default();
+        forever.add_metric(&gauge);
+        for _ in 0..5 {
+            forever.reset();
+        }
+        assert!(forever.gauges.contains_key(&key));
+
+        let config = CacheConfig {
+            gauge_retention: GaugeRetention::Clear,
+            ..CacheConfig::default()
+        };
+        let mut clear = CapellaCache::new(config);
+        clear.add_metric(&gauge);
+        clear.reset();
+        assert!(clear.gauges.is_empty());
+
+        // The gauge survives two flushes without an update and is dropped after the third.
+        let config = CacheConfig {
+            gauge_retention: GaugeRetention::Idle(2),
+            ..CacheConfig::default()
+        };
+        let mut idle = CapellaCache::new(config);
+        idle.add_metric(&gauge);
+        idle.reset();
+        idle.reset();
+        assert!(idle.gauges.contains_key(&key));
+
+        // An update starts the count again.
+        idle.add_metric(&gauge);
+        idle.reset();
+        idle.reset();
+        assert!(idle.gauges.contains_key(&key));
+        idle.reset();
+        assert!(idle.gauges.is_empty());
+    }
+
+    #[test]
+    fn idle_stats_are_kept() {
+        let config = CacheConfig {
+            delete_idle_counters: false,
+            delete_idle_timers: false,
+            delete_idle_sets: false,
+            ..CacheConfig::default()
+        };
+        let mut cache = CapellaCache::new(config);
+        let key = MetricKey::new(Rc::from("test"), &[]);
+
+        let mut set = Metric::new();
+        set.name = "test";
+        set.metric_type = MetricType::Set;
+        cache.add_metric(&set);
+        add_sampled(&mut cache, MetricType::Counter, 1.0, 1.0);
+        cache.add_metric(&make_timer_metric("test", 1.0));
+        cache.reset();
+
+        assert!(cache.counters.get(&key).unwrap().abs() < EPSILON);
+        assert_eq!(cache.sets.get(&key).unwrap().cardinality(), 0.0);
+
+        cache.make_timer_stats();
+        assert!(timer_stat(&cache, "test", "count").abs() < EPSILON);
+        assert!(timer_stat(&cache, "test", "count_ps").abs() < EPSILON);
+    }
+
+    #[test]
+    fn sampled_counters() {
+        let config = CacheConfig { flush_interval: 0.1, ..CacheConfig::default() };
+        let mut cache = CapellaCache::new(config);
+
+        // A counter sampled at 10% represents ten times its value.
+        add_sampled(&mut cache, MetricType::Counter, 1.0, 0.1);
+        add_sampled(&mut cache, MetricType::Counter, 1.0, 1.0);
+
+        let key = MetricKey::new(Rc::from("test"), &[]);
+        let (_, rate) = cache.counter_rates_iter().next().unwrap();
+        assert!((cache.counters.get(&key).unwrap() - 11.0).abs() < 1e-9);
+        assert!((rate - 110.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn sampled_timers() {
+        // This is the StatsD reference case for sampled timers: three times that were sampled
+        // such that they stand in for fifty, with a flush interval of 100ms.
+        let config = CacheConfig {
+            percentiles: vec![90.0, 80.0],
+            flush_interval: 0.1,
+            ..CacheConfig::default()
+        };
+        let mut cache = CapellaCache::new(config);
+        let rate = 3.0 / 50.0;
+        for v in &[100.0, 200.0, 300.0] {
+            add_sampled(&mut cache, MetricType::Timer, *v, rate);
+        }
+        cache.make_timer_stats();
+
+        assert!((timer_stat(&cache, "test", "count") - 50.0).abs() < 1e-9);
+        assert!((timer_stat(&cache, "test", "count_ps") - 500.0).abs() < 1e-9);
+        assert!((timer_stat(&cache, "test", "min") - 100.0).abs() < EPSILON);
+        assert!((timer_stat(&cache, "test", "max") - 300.0).abs() < EPSILON);
+        assert!((timer_stat(&cache, "test", "average") - 200.0).abs() < EPSILON);
+        assert!((timer_stat(&cache, "test", "std_dev") - 81.64965809277261).abs() < 1e-9);
+        assert!((timer_stat(&cache, "test", "upper_90") - 300.0).abs() < EPSILON);
+        assert!((timer_stat(&cache, "test", "mean_90") - 200.0).abs() < EPSILON);
+        assert!((timer_stat(&cache, "test", "sum_90") - 600.0).abs() < EPSILON);
+        assert!((timer_stat(&cache, "test", "upper_80") - 200.0).abs() < EPSILON);
+        assert!((timer_stat(&cache, "test", "mean_80") - 150.0).abs() < EPSILON);
+        assert!((timer_stat(&cache, "test", "sum_80") - 300.0).abs() < EPSILON);
+    }
+
+    #[test]
+    fn single_timer_matches_statsd() {
+        let config = CacheConfig { flush_interval: 0.1, ..CacheConfig::default() };
+        let mut cache = CapellaCache::new(config);
+        cache.add_metric(&make_timer_metric("test", 100.0));
+        cache.make_timer_stats();
+
+        assert!((timer_stat(&cache, "test", "count") - 1.0).abs() < EPSILON);
+        assert!((timer_stat(&cache, "test", "count_ps") - 10.0).abs() < EPSILON);
+        assert!((timer_stat(&cache, "test", "std_dev") - 0.0).abs() < EPSILON);
+        assert!((timer_stat(&cache, "test", "average") - 100.0).abs() < EPSILON);
+    }
+
+    #[test]
+    fn percentiles_of_small_counts() {
+        // A single value is always kept and a percentile that keeps nothing is skipped.
+        assert_eq!(get_percentile(&[7.0], 10.0), Some((7.0, 7.0, 7.0)));
+        assert_eq!(get_percentile(&[1.0, 2.0], 10.0), None);
+        assert_eq!(get_percentile(&[1.0, 2.0], 50.0), Some((1.0, 1.0, 1.0)));
+
+        assert!((get_median(&[7.0]) - 7.0).abs() < EPSILON);
+        assert!((get_median(&[1.0, 2.0]) - 1.5).abs() < EPSILON);
+        assert!((get_median(&[1.0, 2.0, 4.0, 8.0]) - 3.0).abs() < EPSILON);
+    }
+
+    #[test]
+    fn tags_are_canonicalised() {
+        let mut cache = CapellaCache::default();
+        let mut first = Metric::new();
+        first.name = "test";
+        first.value = 1.0;
+        first.raw_tags = "env:prod,az:west";
+
+        let mut second = Metric::new();
+        second.name = "test";
+        second.value = 2.0;
+        second.raw_tags = "az:west,env:prod,az:west";
+
+        let mut untagged = Metric::new();
+        untagged.name = "test";
+        untagged.value = 4.0;
+
+        cache.add_metric(&first);
+        cache.add_metric(&second);
+        cache.add_metric(&untagged);
+
+        let tagged_key = MetricKey::new(Rc::from("test"),
+                                        &[make_tag("az", "west"), make_tag("env", "prod")]);
+        let untagged_key = MetricKey::new(Rc::from("test"), &[]);
+
+        assert_eq!(cache.counters.len(), 2);
+        assert!((cache.counters.get(&tagged_key).unwrap() - 3.0).abs() < EPSILON);
+        assert!((cache.counters.get(&untagged_key).unwrap() - 4.0).abs() < EPSILON);
+
+        // Each spelling of the tags is interned on its own but canonicalises to the same tags, and
+        // the name is interned once. The stored key shares the tags of the first spelling.
+        let interned = &cache.interner.tag_sets;
+        assert_eq!(cache.interner.names.len(), 1);
+        assert_eq!(interned.len(), 2);
+        assert_eq!(interned["env:prod,az:west"], interned["az:west,env:prod,az:west"]);
+        let stored = cache.counters.keys().find(|k| **k == tagged_key).unwrap();
+        assert!(Rc::ptr_eq(&interned["env:prod,az:west"], &stored.tags));
+        assert!(Rc::ptr_eq(cache.interner.names.iter().next().unwrap(), &stored.name));
+    }
+
+    #[test]
+    fn interned_names_are_released() {
+        let config = CacheConfig { delete_idle_counters: false, ..CacheConfig::default() };
+        let mut cache = CapellaCache::new(config);
+        let mut counter = Metric::new();
+        counter.name = "kept";
+        cache.add_metric(&counter);
+        let mut timer = make_timer_metric("dropped", 1.0);
+        timer.raw_tags = "env:prod";
+        cache.add_metric(&timer);
+
+        // Only names and tags that an aggregate still uses survive a flush.
+        cache.reset();
+        assert_eq!(cache.interner.names.iter().map(|n| &**n).collect::<Vec<&str>>(),
+                   vec!["kept"]);
+        assert!(cache.interner.tag_sets.is_empty());
+    }
+
+    #[test]
+    fn timer_histogram_bins() {
+        let config = CacheConfig {
+            histograms: vec![HistogramBins {
+                                 pattern: String::from("api."),
+                                 bins: vec![0.5, 10.0, 100.0, f64::INFINITY],
+                             },
+                             HistogramBins {
+                                 pattern: String::from("api.slow"),
+                                 bins: vec![1000.0],
+                             }],
+            ..CacheConfig::default()
+        };
+        let mut cache = CapellaCache::new(config);
+
+        for v in &[0.5, 3.0, 10.0, 11.0, 99.0, 250.0, 5000.0] {
+            cache.add_metric(&make_timer_metric("api.slow_requests", *v));
+            cache.add_metric(&make_timer_metric("db.query", *v));
+        }
+        cache.make_timer_stats();
+
+        // The first matching pattern wins, even when a later one is more specific.
+        let bin = |name: &str| timer_stat(&cache, "api.slow_requests", name);
+        assert!((bin("histogram.bin_0_5") - 1.0).abs() < EPSILON);
+        assert!((bin("histogram.bin_10") - 2.0).abs() < EPSILON);
+        assert!((bin("histogram.bin_100") - 2.0).abs() < EPSILON);
+        assert!((bin("histogram.bin_inf") - 2.0).abs() < EPSILON);
+
+        let key = MetricKey::new(Rc::from("db.query"), &[]);
+        let stats = cache.timer_data.get(&key).unwrap();
+        assert!(stats.iter().all(|s| !s.0.starts_with("histogram.")));
+    }
+
+    #[test]
+    fn sketched_timers() {
+        let config = CacheConfig {
+            timer_sketch_prefixes: vec![String::from("api.")],
+            histograms: vec![HistogramBins {
+                                 pattern: String::new(),
+                                 bins: vec![100.0, 1000.0, f64::INFINITY],
+                             }],
+            ..CacheConfig::default()
+        };
+        let mut exact = CapellaCache::new(CacheConfig {
+            timer_sketch_prefixes: Vec::new(),
+            ..config.clone()
+        });
+        let mut sketched = CapellaCache::new(config);
+
+        for v in 1..2001 {
+            let mut timer = make_timer_metric("api.latency", f64::from(v) * 0.9);
+            timer.sample_rate = Some(0.5);
+            exact.add_metric(&timer);
+            sketched.add_metric(&timer);
+        }
+        exact.make_timer_stats();
+        sketched.make_timer_stats();
+
+        assert!(sketched.timers.is_empty());
+        for stat in &["min", "max", "count", "count_ps", "average", "std_dev"] {
+            let expected = timer_stat(&exact, "api.latency", stat);
+            assert!((timer_stat(&sketched, "api.latency", stat) - expected).abs() < 1e-6);
+        }
+        for stat in &["median", "upper_95"] {
+            let expected = timer_stat(&exact, "api.latency", stat);
+            let actual = timer_stat(&sketched, "api.latency", stat);
+            assert!((actual - expected).abs() <= 0.01 * expected, "{} was {}", stat, actual);
+        }
+
+        // Bins count the values received whether or not the timer is sketched.
+        let bins = ["histogram.bin_100", "histogram.bin_1000", "histogram.bin_inf"];
+        for bin in &bins {
+            assert_eq!(timer_stat(&sketched, "api.latency", bin),
+                       timer_stat(&exact, "api.latency", bin));
+        }
+        assert_eq!(timer_stat(&sketched, "api.latency", "histogram.bin_100"), 111.0);
+        let total: f64 = bins.iter().map(|b| timer_stat(&sketched, "api.latency", b)).sum();
+        assert!((total - 2000.0).abs() < EPSILON);
+    }
+
+    #[test]
+    fn histograms_and_distributions() {
+        let mut cache = CapellaCache::default();
+
+        for v in 1..101 {
+            let mut histogram = make_timer_metric("bytes", f64::from(v));
+            histogram.metric_type = MetricType::Histogram;
+            cache.add_metric(&histogram);
+
+            let mut distribution = make_timer_metric("lag", f64::from(v));
+            distribution.metric_type = MetricType::Distribution;
+            distribution.sample_rate = Some(0.5);
+            cache.add_metric(&distribution);
+        }
+        cache.make_timer_stats();
+
+        // Histograms are aggregated exactly like timers.
+        assert!((timer_stat(&cache, "bytes", "upper_95") - 95.0).abs() < EPSILON);
+        assert!((timer_stat(&cache, "bytes", "mean_95") - 48.0).abs() < EPSILON);
+
+        // Distributions are approximate and account for sampling in their count.
+        assert!((timer_stat(&cache, "lag", "count") - 200.0).abs() < EPSILON);
+        assert!((timer_stat(&cache, "lag", "min") - 1.0).abs() < EPSILON);
+        assert!((timer_stat(&cache, "lag", "max") - 100.0).abs() < EPSILON);
+        assert!((timer_stat(&cache, "lag", "upper_95") - 95.0).abs() <= 0.95);
+        assert!((timer_stat(&cache, "lag", "median") - 50.0).abs() <= 0.5);
+        let key = MetricKey::new(Rc::from("lag"), &[]);
+        let stats = cache.timer_data.get(&key).unwrap();
+        assert!(stats.iter().all(|s| !s.0.starts_with("mean_")));
+        assert_eq!(cache.distributions_iter().count(), 1);
+    }
+
+    #[test]
+    fn bad_lines_by_source() {
+        let config = CacheConfig { bad_line_log_limit: 2, ..CacheConfig::default() };
+        let mut cache = CapellaCache::new(config);
+        let client = Source::Ip(IpAddr::from([10, 0, 0, 1]));
+        let error = Error::UnknownType { offset: 2 };
+
+        let logged: Vec<bool> = (0..3).map(|_| cache.bad_line_from(client, &error)).collect();
+        assert_eq!(logged, vec![true, true, false]);
+        assert!(cache.bad_line_from(Source::Unix, &error));
+        assert_eq!(cache.bad_sources_iter().collect::<Vec<_>>(),
+                   vec![(&client, &3), (&Source::Unix, &1)]);
+        assert!((cache.total_bad_metrics() - 4.0).abs() < EPSILON);
+
+        // Every source starts over after a flush.
+        cache.reset();
+        assert_eq!(cache.bad_sources_iter().count(), 0);
+        assert!(cache.bad_line_from(client, &error));
+    }
+
+    #[test]
+    fn events_and_service_checks() {
+        let mut cache = CapellaCache::default();
+        for line in &[&b"_e{6,2}:deploy|v2"[..], b"_sc|db.up|1", b"hits:1:2|c"] {
+            cache.add_line(&parse_line(line).unwrap());
+        }
+
+        assert_eq!(cache.events_iter().map(|e| e.title.as_str()).collect::<Vec<_>>(),
+                   vec!["deploy"]);
+        assert_eq!(cache.service_checks_iter().count(), 1);
+        assert!((cache.total_metrics() - 4.0).abs() < EPSILON);
+
+        cache.reset();
+        assert_eq!(cache.events_iter().count(), 0);
+        assert_eq!(cache.service_checks_iter().count(), 0);
+    }
+
+    #[test]
+    fn flush_snapshot() {
+        let config = CacheConfig { delete_idle_counters: false, ..CacheConfig::default() };
+        let mut cache = CapellaCache::new(config);
+        for line in &[&b"hits:5|c"[..], b"users:a|s", b"users:b|s", b"users:a|s", b"req:1:3|ms",
+                      b"_e{1,1}:a|b"] {
+            cache.add_line(&parse_line(line).unwrap());
+        }
+        cache.bad_metric_count_increase(&Error::UnknownType { offset: 2 });
+
+        let snapshot = cache.flush(1500000000);
+        assert_eq!(snapshot.timestamp(), 1500000000);
+        assert_eq!(snapshot.counters_iter().map(|(_, v)| *v).collect::<Vec<_>>(), vec![5.0]);
+        assert_eq!(snapshot.counter_rates_iter().map(|(_, v)| v).collect::<Vec<_>>(), vec![0.5]);
+        assert_eq!(snapshot.sets_iter().map(|(_, v)| *v).collect::<Vec<_>>(), vec![2.0]);
+        let stats = snapshot.timer_data_iter().next().unwrap().1;
+        assert!(stats.contains(&(String::from("max"), 3.0)));
+        assert_eq!(snapshot.events_iter().count(), 1);
+        assert_eq!(snapshot.bad_metrics_iter().collect::<Vec<_>>(), vec![(&"unknown_type", &1)]);
+        assert!((snapshot.total_metrics() - 8.0).abs() < EPSILON);
+
+        // The cache starts the next interval on its own, while the snapshot keeps what it was
+        // handed.
+        assert!(cache.timer_data.is_empty());
+        assert_eq!(cache.events_iter().count(), 0);
+        assert_eq!(cache.counters_iter().map(|(_, v)| *v).collect::<Vec<_>>(), vec![0.0]);
+        assert!(cache.total_metrics().abs() < EPSILON);
     }
 }
@@ -1,17 +1,21 @@
 //! The console module is used mostly for testing purposes.
 #![deny(missing_docs)]
 
-use backend::Backend;
+use backend::{done, Backend, Flush};
 
-use cache::CapellaCache;
+use snapshot::FlushSnapshot;
 
 /// Console is a unit struct that prints stats to the terminal.
 #[derive(Default)]
 pub struct Console;
 
 impl Backend for Console {
-    fn purge_metrics(&self, cache: &mut CapellaCache) {
-        println!("{:?}", cache);
-        cache.reset();
+    fn name(&self) -> &'static str {
+        "console"
+    }
+
+    fn purge_metrics(&self, snapshot: &FlushSnapshot) -> Flush {
+        println!("{:?}", snapshot);
+        done()
     }
 }
@@ -1,47 +1,201 @@
-//! The error module defines custom errors as well as the appropriate conversions needed for
-//! capella.
+//! The error module defines custom errors for capella, which describe why and where a line
+//! could not be parsed.
 #![deny(missing_docs)]
 
 use std::fmt;
 use std::error::Error as StdError;
-use std::num::{ParseFloatError, ParseIntError};
-
-use self::Error::Parse;
 
 /// A type definition for capella's error type.
 pub type CapellaResult<T> = Result<T, Error>;
 
-/// `Error` is used for server side errors that may occur.
-#[derive(Debug)]
+/// `Field` names the part of a line in which a parse error was found.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Field {
+    /// The name of a metric or service check.
+    Name,
+
+    /// A value of a metric, or the member of a set.
+    Value,
+
+    /// The metric type.
+    Type,
+
+    /// The tags of a metric, event or service check.
+    Tags,
+
+    /// The `{title.length,text.length}` header of an event.
+    Lengths,
+
+    /// The title of an event.
+    Title,
+
+    /// The text of an event.
+    Text,
+
+    /// The timestamp of an event or service check.
+    Timestamp,
+
+    /// The status of a service check.
+    Status,
+
+    /// The message of a service check.
+    Message,
+
+    /// One of the optional fields that follow the type of a metric or the text of an event or
+    /// service check, such as `@0.5` or `h:hostname`.
+    Optional,
+}
+
+impl fmt::Display for Field {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match *self {
+            Field::Name => "name",
+            Field::Value => "value",
+            Field::Type => "type",
+            Field::Tags => "tags",
+            Field::Lengths => "lengths",
+            Field::Title => "title",
+            Field::Text => "text",
+            Field::Timestamp => "timestamp",
+            Field::Status => "status",
+            Field::Message => "message",
+            Field::Optional => "optional field",
+        })
+    }
+}
+
+/// `Error` is used for server side errors that may occur. Parse errors carry the field they were
+/// found in and the byte offset within the line at which that field starts, or at which the
+/// invalid UTF-8 sequence starts.
+#[derive(Clone, Debug, Eq, PartialEq)]
 pub enum Error {
-    /// An error that occurs during parsing.
-    Parse,
+    /// A field that is kept as a string was not valid UTF-8.
+    InvalidUtf8 {
+        /// The field holding the invalid sequence.
+        field: Field,
+
+        /// The offset of the first invalid byte.
+        offset: usize,
+    },
+
+    /// A metric or service check name was empty or held a character other than a word character
+    /// or a dot.
+    InvalidName {
+        /// The offset of the name.
+        offset: usize,
+    },
+
+    /// A number was badly formed or out of range, or a service check had an unknown status.
+    InvalidValue {
+        /// The field holding the value.
+        field: Field,
+
+        /// The offset of the value.
+        offset: usize,
+    },
+
+    /// The metric type is not one capella supports.
+    UnknownType {
+        /// The offset of the type.
+        offset: usize,
+    },
+
+    /// The sample rate was not a decimal fraction greater than zero and at most one.
+    InvalidSampleRate {
+        /// The offset of the sample rate.
+        offset: usize,
+    },
+
+    /// A tag had no name.
+    InvalidTag {
+        /// The offset of the tag.
+        offset: usize,
+    },
+
+    /// A field was missing, empty or out of order, or the line held a field that does not belong.
+    Malformed {
+        /// The field that was expected or not understood.
+        field: Field,
+
+        /// The offset at which the field was expected or found.
+        offset: usize,
+    },
+
+    /// A line sent over a stream was longer than the maximum line length.
+    LineTooLong {
+        /// The number of bytes read before the line was given up on.
+        length: usize,
+    },
 }
 
-impl StdError for Error {
-    fn description(&self) -> &str {
+impl Error {
+    /// Return a short name for the kind of error, which is used to count bad metrics by reason.
+    pub fn reason(&self) -> &'static str {
         match *self {
-            Parse => "Error parsing metric",
+            Error::InvalidUtf8 { .. } => "invalid_utf8",
+            Error::InvalidName { .. } => "invalid_name",
+            Error::InvalidValue { .. } => "invalid_value",
+            Error::UnknownType { .. } => "unknown_type",
+            Error::InvalidSampleRate { .. } => "invalid_sample_rate",
+            Error::InvalidTag { .. } => "invalid_tag",
+            Error::Malformed { .. } => "malformed",
+            Error::LineTooLong { .. } => "line_too_long",
         }
     }
-}
 
-impl fmt::Display for Error {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        f.write_str(self.description())
+    /// Return the offset of the error within the line, if it has one.
+    pub fn offset(&self) -> Option<usize> {
+        match *self {
+            Error::InvalidUtf8 { offset, .. } |
+            Error::InvalidName { offset } |
+            Error::InvalidValue { offset, .. } |
+            Error::UnknownType { offset } |
+            Error::InvalidSampleRate { offset } |
+            Error::InvalidTag { offset } |
+            Error::Malformed { offset, .. } => Some(offset),
+            Error::LineTooLong { .. } => None,
+        }
     }
-}
 
-// The following `From` conversions are used for parsing integers or floats while destructuring
-// a packet.
-impl From<ParseIntError> for Error {
-    fn from(_: ParseIntError) -> Error {
-        Error::Parse
+    // Move the offset of an error found in part of a line to be relative to the whole line.
+    pub(crate) fn shift(mut self, by: usize) -> Error {
+        match self {
+            Error::InvalidUtf8 { ref mut offset, .. } |
+            Error::InvalidName { ref mut offset } |
+            Error::InvalidValue { ref mut offset, .. } |
+            Error::UnknownType { ref mut offset } |
+            Error::InvalidSampleRate { ref mut offset } |
+            Error::InvalidTag { ref mut offset } |
+            Error::Malformed { ref mut offset, .. } => *offset += by,
+            Error::LineTooLong { .. } => {}
+        }
+        self
     }
 }
 
-impl From<ParseFloatError> for Error {
-    fn from(_: ParseFloatError) -> Error {
-        Error::Parse
+impl StdError for Error {}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::InvalidUtf8 { field, offset } => {
+                write!(f, "invalid UTF-8 in the {} at byte {}", field, offset)
+            }
+            Error::InvalidName { offset } => write!(f, "invalid name at byte {}", offset),
+            Error::InvalidValue { field, offset } => {
+                write!(f, "invalid {} at byte {}", field, offset)
+            }
+            Error::UnknownType { offset } => write!(f, "unknown metric type at byte {}", offset),
+            Error::InvalidSampleRate { offset } => {
+                write!(f, "invalid sample rate at byte {}", offset)
+            }
+            Error::InvalidTag { offset } => write!(f, "tag without a name at byte {}", offset),
+            Error::Malformed { field, offset } => {
+                write!(f, "missing or unexpected {} at byte {}", field, offset)
+            }
+            Error::LineTooLong { length } => {
+                write!(f, "line longer than the maximum length after {} bytes", length)
+            }
+        }
     }
 }
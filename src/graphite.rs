@@ -1,92 +1,1045 @@
 //! The graphite module is the default backend for capella.
 #![deny(missing_docs)]
 
-use std::io;
+use std::cell::{Cell, RefCell};
+use std::collections::VecDeque;
+use std::convert::TryFrom;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Read, Seek, SeekFrom, Write};
 use std::net::{SocketAddr, ToSocketAddrs};
+use std::path::PathBuf;
+use std::rc::Rc;
+use std::str::{self, FromStr};
+use std::time::{Duration, Instant};
 
-use chrono::offset::local::Local;
+use bytes::Bytes;
 
+use futures::future::{self, Loop};
 use futures::Future;
 
 use tokio_core::net::TcpStream;
-use tokio_core::reactor::Core;
+use tokio_core::reactor::Handle;
 
-use backend::Backend;
+use tokio_io::io::write_all;
 
-use cache::CapellaCache;
+use backend::{done, not_started, send_datagrams, with_timeout, Backend, Flush,
+              DEFAULT_CONNECT_TIMEOUT_SECS, DEFAULT_WRITE_TIMEOUT_SECS};
 
-const CAPELLA_METRICS_TOTAL: &'static str = "capella.total_metrics";
-const CAPELLA_BAD_METRICS_TOTAL: &'static str = "capella.bad_metrics";
-const COUNT_SUFFIX: &'static str = ".count";
+use cache::{backend_tag, source_tag};
+
+use parse::Tag;
+
+use snapshot::FlushSnapshot;
+
+const CAPELLA_METRICS_TOTAL: &str = "capella.total_metrics";
+const CAPELLA_BAD_METRICS_TOTAL: &str = "capella.bad_metrics";
+const CAPELLA_BAD_METRICS_BY_SOURCE: &str = "capella.bad_metrics_by_source";
+const CAPELLA_BACKEND: &str = "capella.backend";
+const CAPELLA_GRAPHITE: &str = "capella.graphite";
+const COUNT_SUFFIX: &str = "count";
+
+/// The prefix counter rates are written under when none is given, from the StatsD legacy namespace.
+pub const DEFAULT_RATE_PREFIX: &str = "stats";
+
+/// The prefix raw counter counts are written under when none is given.
+pub const DEFAULT_COUNT_PREFIX: &str = "stats_counts";
+
+// How many undelivered lines are kept in memory to be retried when no limit is configured.
+const DEFAULT_MAX_QUEUED_LINES: u64 = 100_000;
+
+/// The size a spill file may grow to when no limit is given.
+pub const DEFAULT_SPILL_MAX_BYTES: u64 = 64 * 1024 * 1024;
+
+// The most bytes read back from the spill file at once, unless a single line is longer, so that
+// reading it never holds up the event loop for long.
+const MAX_SPILL_CHUNK_BYTES: usize = 1024 * 1024;
+
+// How long to wait before connecting again after a failed flush. The wait doubles with every
+// failure in a row, up to the maximum.
+const MIN_BACKOFF_MILLIS: u64 = 500;
+const MAX_BACKOFF_SECS: u64 = 60;
+
+// The most metrics put in a single pickle message, which keeps messages well below the 1 MiB
+// carbon accepts.
+const PICKLE_BATCH_SIZE: usize = 500;
+
+// The pickle opcodes used to encode a list of `(path, (timestamp, value))` tuples.
+const PICKLE_PROTO: u8 = 0x80;
+const PICKLE_EMPTY_LIST: u8 = b']';
+const PICKLE_MARK: u8 = b'(';
+const PICKLE_BINUNICODE: u8 = b'X';
+const PICKLE_BININT: u8 = b'J';
+const PICKLE_LONG1: u8 = 0x8a;
+const PICKLE_BINFLOAT: u8 = b'G';
+const PICKLE_TUPLE2: u8 = 0x86;
+const PICKLE_APPENDS: u8 = b'e';
+const PICKLE_STOP: u8 = b'.';
+
+/// `Protocol` describes how metrics are delivered to graphite.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Protocol {
+    /// Newline delimited lines over a TCP connection, usually to port 2003.
+    Plaintext,
+
+    /// Batches of metrics in the pickle format over a TCP connection, usually to port 2004.
+    Pickle,
+
+    /// Newline delimited lines packed into UDP datagrams. Nothing tells capella whether they
+    /// arrived, so only lines that could not be sent at all are retried.
+    Udp,
+}
+
+impl FromStr for Protocol {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "plaintext" => Ok(Protocol::Plaintext),
+            "pickle" => Ok(Protocol::Pickle),
+            "udp" => Ok(Protocol::Udp),
+            _ => Err(io::Error::new(io::ErrorKind::InvalidInput, "invalid graphite protocol")),
+        }
+    }
+}
 
 /// The backend to a graphite server.
 #[derive(Debug)]
 pub struct Graphite {
     addr: SocketAddr,
+    protocol: Protocol,
+    rate_prefix: String,
+    count_prefix: String,
+    connect_timeout: Duration,
+    write_timeout: Duration,
+    max_queued_lines: u64,
+    spill: Option<Rc<Spill>>,
+    connection: Rc<RefCell<Connection>>,
+    handle: Option<Handle>,
+}
+
+// `Payload` holds the lines of a single flush until they are delivered to graphite.
+#[derive(Debug)]
+struct Payload {
+    data: Bytes,
+    lines: u64,
+
+    // Whether an earlier attempt to deliver the lines failed.
+    retried: bool,
+}
+
+impl Payload {
+    fn new(data: Bytes, retried: bool) -> Payload {
+        let lines = data.iter().filter(|b| **b == b'\n').count() as u64;
+        Payload { data, lines, retried }
+    }
+}
+
+// `Spill` is a file that holds the payloads the retry queue has no room for. It is read back a
+// chunk at a time and removed once every line in it has been read.
+#[derive(Debug)]
+struct Spill {
+    path: PathBuf,
+    max_bytes: u64,
+
+    // How far into the file lines have been read back.
+    read_offset: Cell<u64>,
+}
+
+impl Spill {
+    // Append a payload to the file, returning whether there was room for it.
+    fn write(&self, data: &[u8]) -> io::Result<bool> {
+        let size = match fs::metadata(&self.path) {
+            Ok(metadata) => metadata.len(),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => 0,
+            Err(e) => return Err(e),
+        };
+        if size + data.len() as u64 > self.max_bytes {
+            return Ok(false);
+        }
+
+        OpenOptions::new().create(true).append(true).open(&self.path)?.write_all(data)?;
+        Ok(true)
+    }
+
+    // Read back the next chunk of at most `max_lines` lines, and of at most
+    // `MAX_SPILL_CHUNK_BYTES` unless a single line is longer. The file is removed once it has been
+    // read to the end.
+    fn take(&self, max_lines: u64) -> io::Result<Option<Bytes>> {
+        let file = match File::open(&self.path) {
+            Ok(file) => file,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+                self.read_offset.set(0);
+                return Ok(None);
+            }
+            Err(e) => return Err(e),
+        };
+        let size = file.metadata()?.len();
+        let mut reader = BufReader::new(file);
+        reader.seek(SeekFrom::Start(self.read_offset.get()))?;
+
+        // At least one line is read so that a queue limit of zero still drains the file.
+        let mut data = Vec::new();
+        let mut lines = 0;
+        while lines < max_lines.max(1) && data.len() < MAX_SPILL_CHUNK_BYTES {
+            if reader.read_until(b'\n', &mut data)? == 0 {
+                break;
+            }
+            lines += 1;
+        }
+
+        let offset = self.read_offset.get() + data.len() as u64;
+        if offset >= size {
+            fs::remove_file(&self.path)?;
+            self.read_offset.set(0);
+        } else {
+            self.read_offset.set(offset);
+        }
+
+        Ok(Some(Bytes::from(data)).filter(|d| !d.is_empty()))
+    }
+}
+
+// `Connection` is the state shared by every flush: the connection kept open between flushes, the
+// payloads waiting to be retried and the counts reported about them.
+#[derive(Debug, Default)]
+struct Connection {
+    stream: Option<TcpStream>,
+    queue: VecDeque<Payload>,
+    queued_lines: u64,
+    failures: u32,
+    retry_at: Option<Instant>,
+    draining: bool,
+    retried_lines: u64,
+    dropped_lines: u64,
+}
+
+impl Connection {
+    // Queue a payload behind the others.
+    fn push(&mut self, payload: Payload, max_lines: u64, spill: Option<&Spill>) {
+        self.queued_lines += payload.lines;
+        self.queue.push_back(payload);
+        self.shrink(max_lines, spill);
+    }
+
+    // Put a payload that could not be delivered back at the front of the queue, so it is the
+    // first one sent once graphite can be reached again.
+    fn push_front(&mut self, mut payload: Payload, max_lines: u64, spill: Option<&Spill>) {
+        payload.retried = true;
+        self.queued_lines += payload.lines;
+        self.queue.push_front(payload);
+        self.shrink(max_lines, spill);
+    }
+
+    // Make room in the queue by moving the oldest payloads to the spill file, or dropping them
+    // when there is none or it is full.
+    fn shrink(&mut self, max_lines: u64, spill: Option<&Spill>) {
+        while self.queued_lines > max_lines {
+            let payload = match self.queue.pop_front() {
+                Some(payload) => payload,
+                None => break,
+            };
+            self.queued_lines -= payload.lines;
+
+            let spilled = spill.map_or(Ok(false), |spill| spill.write(&payload.data));
+            match spilled {
+                Ok(true) => {}
+                Ok(false) => self.dropped_lines += payload.lines,
+                Err(e) => {
+                    error!("failed to spill graphite lines to disk: {}", e);
+                    self.dropped_lines += payload.lines;
+                }
+            }
+        }
+    }
+
+    // Take the next payload to send. Once the queue is empty the next chunk of at most `max_lines`
+    // spilled lines is read back, so older lines can arrive after newer ones, which graphite
+    // orders by their timestamps.
+    fn pop(&mut self, max_lines: u64, spill: Option<&Spill>) -> Option<Payload> {
+        if let Some(payload) = self.queue.pop_front() {
+            self.queued_lines -= payload.lines;
+            return Some(payload);
+        }
+
+        match spill.map(|spill| spill.take(max_lines)) {
+            Some(Ok(data)) => data.map(|data| Payload::new(data, true)),
+            Some(Err(e)) => {
+                error!("failed to read graphite lines back from disk: {}", e);
+                None
+            }
+            None => None,
+        }
+    }
+
+    // Mark every queued payload as held over from a flush that failed.
+    fn defer(&mut self) {
+        for payload in &mut self.queue {
+            payload.retried = true;
+        }
+    }
+
+    // Forget the connection after a failed delivery and wait longer before connecting again.
+    fn failed(&mut self) {
+        self.defer();
+        self.stream = None;
+        self.failures += 1;
+        let backoff = Duration::from_millis(MIN_BACKOFF_MILLIS << (self.failures - 1).min(16));
+        self.retry_at = Some(Instant::now() + backoff.min(Duration::from_secs(MAX_BACKOFF_SECS)));
+    }
 }
 
 impl Graphite {
     /// Construct a new graphite instance with a given address.
     pub fn new<A: ToSocketAddrs>(addr: A) -> io::Result<Graphite> {
-        Ok(Graphite { addr: addr.to_socket_addrs()?.next().unwrap() })
+        Ok(Graphite {
+            addr: addr.to_socket_addrs()?.next().unwrap(),
+            protocol: Protocol::Plaintext,
+            rate_prefix: String::from(DEFAULT_RATE_PREFIX),
+            count_prefix: String::from(DEFAULT_COUNT_PREFIX),
+            connect_timeout: Duration::from_secs(DEFAULT_CONNECT_TIMEOUT_SECS),
+            write_timeout: Duration::from_secs(DEFAULT_WRITE_TIMEOUT_SECS),
+            max_queued_lines: DEFAULT_MAX_QUEUED_LINES,
+            spill: None,
+            connection: Rc::new(RefCell::new(Connection::default())),
+            handle: None,
+        })
+    }
+
+    /// Set the protocol used to deliver metrics. The default is plaintext over TCP.
+    pub fn with_protocol(mut self, protocol: Protocol) -> Graphite {
+        self.protocol = protocol;
+        self
+    }
+
+    /// Set the namespaces under which counter rates and raw counter values are written. An
+    /// empty prefix writes the counter under its own name.
+    pub fn with_counter_prefixes(mut self, rate_prefix: &str, count_prefix: &str) -> Graphite {
+        self.rate_prefix = String::from(rate_prefix);
+        self.count_prefix = String::from(count_prefix);
+        self
     }
 
-    // Construct a string for the graphite new line API.
-    fn make_metric_string(&self, name: &str, value: &f64, time: &str) -> String {
+    /// Set how long a flush may take to connect to graphite and how long it may take to write
+    /// the payload once connected. A flush that runs out of time fails and its lines are retried
+    /// with the next flush.
+    pub fn with_timeouts(mut self, connect_timeout: Duration, write_timeout: Duration) -> Graphite {
+        self.connect_timeout = connect_timeout;
+        self.write_timeout = write_timeout;
+        self
+    }
+
+    /// Set how many lines that could not be delivered are kept in memory to be retried. Once the
+    /// queue is full the oldest lines are spilled to disk if a spill file is set, and dropped if
+    /// not.
+    pub fn with_queue_limit(mut self, max_lines: u64) -> Graphite {
+        self.max_queued_lines = max_lines;
+        self
+    }
+
+    /// Spill the lines that do not fit in the retry queue to a file, which never grows beyond the
+    /// given size. Once the queue has been delivered, each flush reads back at most a queue's
+    /// worth of lines from the file, which is removed when all of them have been read.
+    pub fn with_spill<P: Into<PathBuf>>(mut self, path: P, max_bytes: u64) -> Graphite {
+        self.spill = Some(Rc::new(Spill {
+            path: path.into(),
+            max_bytes,
+            read_offset: Cell::new(0),
+        }));
+        self
+    }
+
+    // Construct a string for the graphite new line API. An optional suffix is appended to the
+    // name and tags are written using the graphite 1.1 `;tag=value` syntax. Tags without a value,
+    // or with an empty one, are given a value of `true` since graphite requires one.
+    fn make_metric_string(&self,
+                          name: &str,
+                          suffix: Option<&str>,
+                          tags: &[Tag],
+                          value: &f64,
+                          time: &str)
+                          -> String {
         let mut s = String::new();
         s.push_str(name);
-        s.push_str(" ");
+        if let Some(suffix) = suffix {
+            s.push('.');
+            s.push_str(suffix);
+        }
+        for tag in tags {
+            let value = tag.value.as_deref().filter(|v| !v.is_empty());
+            s.push(';');
+            s.push_str(&sanitize_tag_name(&tag.name));
+            s.push('=');
+            s.push_str(&sanitize_tag_value(value.unwrap_or("true")));
+        }
+        s.push(' ');
         s.push_str(&value.to_string());
-        s.push_str(" ");
+        s.push(' ');
         s.push_str(time);
-        s.push_str("\n");
+        s.push('\n');
 
         s
     }
-}
-
-impl Backend for Graphite {
-    fn purge_metrics(&self, cache: &mut CapellaCache) {
-        let mut core = Core::new().unwrap();
-        let handle = core.handle();
 
-        let unix_time = Local::now().timestamp().to_string();
+    // Build the whole payload for a flush. Every counter is written twice: once as a per second
+    // rate and once as the raw count.
+    fn make_buffer(&self, snapshot: &FlushSnapshot) -> String {
+        let unix_time = &snapshot.timestamp().to_string();
         let mut buffer = String::new();
-        cache.make_timer_stats();
 
-        for (k, v) in cache.counters_iter() {
-            let metric_str = self.make_metric_string(k, v, &unix_time);
+        for (k, v) in snapshot.counter_rates_iter() {
+            let name = prefixed(&self.rate_prefix, k.name());
+            let metric_str = self.make_metric_string(&name, None, k.tags(), &v, unix_time);
             buffer.push_str(&metric_str);
         }
 
-        for (k, v) in cache.gauges_iter() {
-            let metric_str = self.make_metric_string(k, v, &unix_time);
+        for (k, v) in snapshot.counters_iter() {
+            let name = prefixed(&self.count_prefix, k.name());
+            let metric_str = self.make_metric_string(&name, None, k.tags(), v, unix_time);
             buffer.push_str(&metric_str);
         }
 
-        for (k, v) in cache.timer_data_iter() {
-            let metric_str = self.make_metric_string(k, v, &unix_time);
+        for (k, v) in snapshot.gauges_iter() {
+            let metric_str = self.make_metric_string(k.name(), None, k.tags(), v, unix_time);
             buffer.push_str(&metric_str);
         }
 
-        for (k, v) in cache.sets_iter() {
-            let key = String::from(&*k.clone().as_str()) + COUNT_SUFFIX;
-            let value = v.len() as f64;
-            let metric_str = self.make_metric_string(&key, &value, &unix_time);
+        for (k, stats) in snapshot.timer_data_iter() {
+            for (stat, v) in stats {
+                let metric_str =
+                    self.make_metric_string(k.name(), Some(stat), k.tags(), v, unix_time);
+                buffer.push_str(&metric_str);
+            }
+        }
+
+        for (k, v) in snapshot.sets_iter() {
+            let metric_str =
+                self.make_metric_string(k.name(), Some(COUNT_SUFFIX), k.tags(), v, unix_time);
             buffer.push_str(&metric_str);
         }
 
         // Add our total message and bad message counts.
         buffer.push_str(&self.make_metric_string(CAPELLA_METRICS_TOTAL,
-                                                 &cache.total_metrics(), &unix_time));
+                                                 None,
+                                                 &[],
+                                                 &snapshot.total_metrics(),
+                                                 unix_time));
         buffer.push_str(&self.make_metric_string(CAPELLA_BAD_METRICS_TOTAL,
-                                                 &cache.total_bad_metrics(),
-                                                 &unix_time));
+                                                 None,
+                                                 &[],
+                                                 &snapshot.total_bad_metrics(),
+                                                 unix_time));
+        for (reason, count) in snapshot.bad_metrics_iter() {
+            buffer.push_str(&self.make_metric_string(CAPELLA_BAD_METRICS_TOTAL,
+                                                     Some(reason),
+                                                     &[],
+                                                     &(*count as f64),
+                                                     unix_time));
+        }
+        for (source, count) in snapshot.bad_sources_iter() {
+            buffer.push_str(&self.make_metric_string(CAPELLA_BAD_METRICS_BY_SOURCE,
+                                                     None,
+                                                     &[source_tag(source)],
+                                                     &(*count as f64),
+                                                     unix_time));
+        }
+        for (backend, stats) in snapshot.backend_stats_iter() {
+            let tags = [backend_tag(backend)];
+            let latency = stats.max_latency.as_secs_f64() * 1000.0;
+            for &(suffix, value) in &[("flushes", stats.flushes as f64),
+                                      ("flush_errors", stats.errors as f64),
+                                      ("flush_latency_ms", latency)] {
+                buffer.push_str(&self.make_metric_string(CAPELLA_BACKEND,
+                                                         Some(suffix),
+                                                         &tags,
+                                                         &value,
+                                                         unix_time));
+            }
+        }
+
+        // Add the lines this backend is holding on to and what became of the lines it retried.
+        let connection = self.connection.borrow();
+        for &(suffix, value) in &[("queued_lines", connection.queued_lines),
+                                  ("retried_lines", connection.retried_lines),
+                                  ("dropped_lines", connection.dropped_lines)] {
+            buffer.push_str(&self.make_metric_string(CAPELLA_GRAPHITE,
+                                                     Some(suffix),
+                                                     &[],
+                                                     &(value as f64),
+                                                     unix_time));
+        }
+
+        buffer
+    }
+
+    // Send the queued payloads over the kept connection until the queue is empty, connecting
+    // again when needed. A payload that cannot be delivered is put back for the next flush. Only
+    // one drain runs at a time, and a flush that finds one running leaves its payload to it. Each
+    // drain reads back at most one chunk of the spill file, so a large spill is delivered over
+    // several flushes.
+    fn drain(&self, handle: Handle) -> Flush {
+        {
+            let mut connection = self.connection.borrow_mut();
+            if connection.draining {
+                return done();
+            }
+            if connection.retry_at.is_some_and(|at| Instant::now() < at) {
+                connection.defer();
+                let reason = format!("waiting to reconnect to graphite after {} failures with {} \
+                                      lines queued",
+                                     connection.failures,
+                                     connection.queued_lines);
+                return Box::new(future::err(io::Error::other(reason)));
+            }
+            connection.draining = true;
+        }
+
+        let connection = self.connection.clone();
+        let finished = self.connection.clone();
+        let spill = self.spill.clone();
+        let (addr, protocol) = (self.addr, self.protocol);
+        let (connect_timeout, write_timeout) = (self.connect_timeout, self.write_timeout);
+        let max_lines = self.max_queued_lines;
+
+        // Kept connections are checked from within the event loop, so the loop is started lazily.
+        let drain = future::lazy(move || future::loop_fn(false, move |read_spill| -> SendNext {
+            let (payload, from_spill) = {
+                let mut connection = connection.borrow_mut();
+                let from_spill = connection.queue.is_empty();
+                match connection.pop(max_lines, spill.as_deref().filter(|_| !read_spill)) {
+                    Some(payload) => (payload, from_spill),
+                    None => return Box::new(future::ok(Loop::Break(()))),
+                }
+            };
+
+            let kept = connection.borrow_mut().stream.take().filter(is_open);
+            let send: Delivery = match protocol {
+                Protocol::Udp => {
+                    let send = send_datagrams(addr, &payload.data, write_timeout, &handle);
+                    Box::new(send.map(|_| None))
+                }
+                Protocol::Plaintext | Protocol::Pickle => {
+                    let connect = match kept {
+                        Some(stream) => Box::new(future::ok(stream)),
+                        None => {
+                            let connect = TcpStream::connect(&addr, &handle);
+                            with_timeout(connect, connect_timeout, &handle,
+                                         "connecting to graphite")
+                        }
+                    };
+                    let data = match protocol {
+                        Protocol::Pickle => Bytes::from(make_pickle(&payload.data)),
+                        _ => payload.data.clone(),
+                    };
+
+                    let inner = handle.clone();
+                    Box::new(connect
+                        .and_then(move |out| {
+                            with_timeout(write_all(out, data), write_timeout, &inner,
+                                         "writing to graphite")
+                        })
+                        .map(|(out, _)| Some(out)))
+                }
+            };
+
+            let connection = connection.clone();
+            let spill = spill.clone();
+            Box::new(send
+                .then(move |res| {
+                    let mut connection = connection.borrow_mut();
+                    match res {
+                        Ok(out) => {
+                            connection.stream = out;
+                            connection.failures = 0;
+                            connection.retry_at = None;
+                            if payload.retried {
+                                connection.retried_lines += payload.lines;
+                            }
+                            Ok(Loop::Continue(read_spill || from_spill))
+                        }
+                        Err(e) => {
+                            // The whole payload is sent again even if part of it was written,
+                            // since graphite keeps a single value for each name and timestamp.
+                            connection.failed();
+                            connection.push_front(payload, max_lines, spill.as_deref());
+                            Err(e)
+                        }
+                    }
+                }))
+        }));
+
+        Box::new(drain.then(move |res| {
+            finished.borrow_mut().draining = false;
+            res
+        }))
+    }
+}
+
+// A single step of a drain, which sends one payload.
+type SendNext = Box<dyn Future<Item = Loop<(), bool>, Error = io::Error>>;
+
+// Delivering a payload, which resolves to the connection to keep for the next one, if any.
+type Delivery = Box<dyn Future<Item = Option<TcpStream>, Error = io::Error>>;
+
+// Encode plaintext lines as pickle messages. Each message is a 4 byte big endian length followed
+// by a protocol 2 pickle of a list of `(path, (timestamp, value))` tuples, which is what the carbon
+// pickle receiver expects. Lines that cannot be split into a path, value and timestamp are skipped.
+fn make_pickle(lines: &[u8]) -> Vec<u8> {
+    let metrics: Vec<(&str, f64, i64)> = lines.split(|b| *b == b'\n')
+        .filter_map(|line| str::from_utf8(line).ok().and_then(split_plaintext_line))
+        .collect();
+
+    let mut messages = Vec::new();
+    for batch in metrics.chunks(PICKLE_BATCH_SIZE) {
+        let mut pickle = vec![PICKLE_PROTO, 2, PICKLE_EMPTY_LIST, PICKLE_MARK];
+        for &(path, value, timestamp) in batch {
+            pickle.push(PICKLE_BINUNICODE);
+            pickle.extend_from_slice(&(path.len() as u32).to_le_bytes());
+            pickle.extend_from_slice(path.as_bytes());
+
+            // Timestamps only need more than 4 bytes after 2038.
+            match i32::try_from(timestamp) {
+                Ok(timestamp) => {
+                    pickle.push(PICKLE_BININT);
+                    pickle.extend_from_slice(&timestamp.to_le_bytes());
+                }
+                Err(_) => {
+                    pickle.extend_from_slice(&[PICKLE_LONG1, 8]);
+                    pickle.extend_from_slice(&timestamp.to_le_bytes());
+                }
+            }
+
+            pickle.push(PICKLE_BINFLOAT);
+            pickle.extend_from_slice(&value.to_be_bytes());
+            pickle.extend_from_slice(&[PICKLE_TUPLE2, PICKLE_TUPLE2]);
+        }
+        pickle.extend_from_slice(&[PICKLE_APPENDS, PICKLE_STOP]);
+
+        messages.extend_from_slice(&(pickle.len() as u32).to_be_bytes());
+        messages.extend_from_slice(&pickle);
+    }
+
+    messages
+}
+
+// Split a plaintext line into its path, value and timestamp.
+fn split_plaintext_line(line: &str) -> Option<(&str, f64, i64)> {
+    let mut parts = line.rsplitn(3, ' ');
+    let timestamp = parts.next()?.parse().ok()?;
+    let value = parts.next()?.parse().ok()?;
+    Some((parts.next()?, value, timestamp))
+}
+
+impl Backend for Graphite {
+    fn name(&self) -> &'static str {
+        "graphite"
+    }
+
+    fn start(&mut self, handle: &Handle) {
+        self.handle = Some(handle.clone());
+    }
+
+    // The payload is written on the event loop, so a slow or unreachable graphite server only
+    // delays this backend's flush and never the sockets capella reads from.
+    fn purge_metrics(&self, snapshot: &FlushSnapshot) -> Flush {
+        let handle = match self.handle {
+            Some(ref handle) => handle.clone(),
+            None => return Box::new(future::err(not_started())),
+        };
+
+        let payload = Payload::new(Bytes::from(self.make_buffer(snapshot)), false);
+        {
+            // The counts were just reported, so they start over with this flush.
+            let mut connection = self.connection.borrow_mut();
+            connection.retried_lines = 0;
+            connection.dropped_lines = 0;
+            connection.push(payload, self.max_queued_lines, self.spill.as_deref());
+        }
+
+        self.drain(handle)
+    }
+}
+
+// Whether a connection kept from an earlier flush is still open. Graphite never writes back, so a
+// read that reaches the end of the stream means the server closed the connection.
+fn is_open(stream: &TcpStream) -> bool {
+    let mut stream = stream;
+    match stream.read(&mut [0; 1]) {
+        Ok(0) => false,
+        Ok(_) => true,
+        Err(e) => e.kind() == io::ErrorKind::WouldBlock,
+    }
+}
+
+// Graphite 1.1 does not allow `;`, `!`, `^` or `=` in tag names, and the plaintext protocol splits
+// lines on whitespace. Each of them is replaced with an `_`.
+fn sanitize_tag_name(name: &str) -> String {
+    name.replace(|c: char| c.is_whitespace() || [';', '!', '^', '='].contains(&c), "_")
+}
+
+// Tag values may hold anything but `;` and whitespace, as long as they do not start with `~`.
+fn sanitize_tag_value(value: &str) -> String {
+    let value = value.replace(|c: char| c.is_whitespace() || c == ';', "_");
+    match value.strip_prefix('~') {
+        Some(rest) => format!("_{}", rest),
+        None => value,
+    }
+}
+
+// Join a namespace prefix and a metric name.
+fn prefixed(prefix: &str, name: &str) -> String {
+    if prefix.is_empty() {
+        return String::from(name);
+    }
+    format!("{}.{}", prefix, name)
+}
+
+#[cfg(test)]
+mod tests {
+
+    use std::cell::Cell;
+    use std::convert::TryFrom;
+    use std::env;
+    use std::fs;
+    use std::io::{ErrorKind, Read};
+    use std::net::{IpAddr, TcpListener, UdpSocket};
+    use std::process;
+    use std::time::Duration;
+
+    use bytes::Bytes;
+
+    use tokio_core::reactor::Core;
+
+    use super::{make_pickle, Connection, Graphite, Payload, Protocol, Spill,
+                DEFAULT_SPILL_MAX_BYTES};
+    use backend::Backend;
+    use cache::{CacheConfig, CapellaCache, Source};
+    use error::Error;
+    use parse::{parse_line, Metric, Tag};
+
+    #[test]
+    fn counter_rate_and_count() {
+        let graphite = Graphite::new("127.0.0.1:2003").unwrap();
+        let config = CacheConfig { flush_interval: 10.0, ..CacheConfig::default() };
+        let mut cache = CapellaCache::new(config);
+
+        let mut counter = Metric::new();
+        counter.name = "hits";
+        counter.value = 25.0;
+        cache.add_metric(&counter);
+        let snapshot = cache.flush(1500000000);
+
+        let buffer = graphite.make_buffer(&snapshot);
+        assert!(buffer.contains("stats.hits 2.5 1500000000\n"));
+        assert!(buffer.contains("stats_counts.hits 25 1500000000\n"));
+
+        let graphite = graphite.with_counter_prefixes("rates", "");
+        let buffer = graphite.make_buffer(&snapshot);
+        assert!(buffer.contains("rates.hits 2.5 1500000000\n"));
+        assert!(buffer.contains("\nhits 25 1500000000\n"));
+    }
+
+    #[test]
+    fn bad_metrics_by_reason() {
+        let graphite = Graphite::new("127.0.0.1:2003").unwrap();
+        let mut cache = CapellaCache::default();
+        for line in &[&b"a:1|x"[..], b"b:1|y", b"c:1|c|@2.0"] {
+            cache.bad_metric_count_increase(&parse_line(line).unwrap_err());
+        }
+
+        let buffer = graphite.make_buffer(&cache.flush(1500000000));
+        assert!(buffer.contains("capella.bad_metrics 3 1500000000\n"));
+        assert!(buffer.contains("capella.bad_metrics.unknown_type 2 1500000000\n"));
+        assert!(buffer.contains("capella.bad_metrics.invalid_sample_rate 1 1500000000\n"));
+
+        let source = Source::Ip(IpAddr::from([10, 0, 0, 1]));
+        cache.bad_line_from(source, &Error::LineTooLong { length: 9 });
+        let buffer = graphite.make_buffer(&cache.flush(1500000000));
+        assert!(buffer.contains("capella.bad_metrics_by_source;source=10.0.0.1 1 1500000000\n"));
+    }
+
+    #[test]
+    fn backend_stats() {
+        let graphite = Graphite::new("127.0.0.1:2003").unwrap();
+        let mut cache = CapellaCache::default();
+        cache.backend_flush_finished("influx", Duration::from_millis(30), false);
+        cache.backend_flush_finished("influx", Duration::from_millis(120), true);
+
+        let buffer = graphite.make_buffer(&cache.flush(1500000000));
+        assert!(buffer.contains("capella.backend.flushes;backend=influx 2 1500000000\n"));
+        assert!(buffer.contains("capella.backend.flush_errors;backend=influx 1 1500000000\n"));
+        assert!(buffer.contains("capella.backend.flush_latency_ms;backend=influx 120 1500000000"));
+    }
+
+    #[test]
+    fn flush_on_the_event_loop() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut graphite = Graphite::new(listener.local_addr().unwrap()).unwrap();
+        let mut core = Core::new().unwrap();
+        graphite.start(&core.handle());
+
+        let mut cache = CapellaCache::default();
+        cache.add_line(&parse_line(b"temp:21|g").unwrap());
+        core.run(graphite.purge_metrics(&cache.flush(1500000000))).unwrap();
+
+        // The connection is kept open until the backend goes away.
+        drop(graphite);
+        let mut payload = String::new();
+        listener.accept().unwrap().0.read_to_string(&mut payload).unwrap();
+        assert!(payload.starts_with("temp 21 1500000000\n"));
+    }
+
+    fn make_payload(lines: &str) -> Payload {
+        Payload::new(Bytes::from(lines), false)
+    }
+
+    #[test]
+    fn retry_queue_is_bounded() {
+        let mut connection = Connection::default();
+        connection.push(make_payload("a 1 1\nb 1 1\n"), 3, None);
+        connection.push(make_payload("c 1 2\n"), 3, None);
+        connection.push(make_payload("d 1 3\ne 1 3\n"), 3, None);
+
+        assert_eq!(connection.queued_lines, 3);
+        assert_eq!(connection.dropped_lines, 2);
+        assert_eq!(connection.pop(3, None).unwrap().data, "c 1 2\n");
+        assert_eq!(connection.queued_lines, 2);
+    }
+
+    fn make_spill(name: &str, max_bytes: u64) -> Spill {
+        let file = format!("capella-graphite-{}-{}.spill", name, process::id());
+        let path = env::temp_dir().join(file);
+        drop(fs::remove_file(&path));
+        Spill { path, max_bytes, read_offset: Cell::new(0) }
+    }
+
+    #[test]
+    fn retry_queue_spills_to_disk() {
+        let spill = make_spill("queue", 12);
+        let mut connection = Connection::default();
+        for lines in &["a 1 1\n", "b 1 2\n", "c 1 3\n", "d 1 4\n"] {
+            connection.push(make_payload(lines), 1, Some(&spill));
+        }
+
+        // The file only has room for the first two payloads pushed out of the queue, and it is
+        // read back no more than a queue's worth of lines at a time.
+        assert_eq!(connection.dropped_lines, 1);
+        assert_eq!(connection.pop(1, Some(&spill)).unwrap().data, "d 1 4\n");
+        let spilled = connection.pop(1, Some(&spill)).unwrap();
+        assert_eq!(spilled.data, "a 1 1\n");
+        assert_eq!(spilled.lines, 1);
+        assert!(spilled.retried);
+        assert!(spill.path.exists());
+        assert_eq!(connection.pop(1, Some(&spill)).unwrap().data, "b 1 2\n");
+        assert!(!spill.path.exists());
+        assert!(connection.pop(1, Some(&spill)).is_none());
+    }
+
+    #[test]
+    fn large_spill_drains_over_several_flushes() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let spill = make_spill("drain", DEFAULT_SPILL_MAX_BYTES);
+        let spilled: String = (0..25).map(|i| format!("s {} 1500000000\n", i)).collect();
+        fs::write(&spill.path, &spilled).unwrap();
+
+        let mut graphite = Graphite::new(listener.local_addr().unwrap())
+            .unwrap()
+            .with_queue_limit(10)
+            .with_spill(&spill.path, DEFAULT_SPILL_MAX_BYTES);
+        let mut core = Core::new().unwrap();
+        graphite.start(&core.handle());
+
+        // Every flush delivers its own lines and at most ten of the spilled ones.
+        for remaining in &[true, true, false] {
+            core.run(graphite.purge_metrics(&CapellaCache::default().flush(1500000000))).unwrap();
+            assert_eq!(spill.path.exists(), *remaining);
+        }
+        assert_eq!(graphite.connection.borrow().retried_lines, 5);
+
+        drop(graphite);
+        let mut payload = String::new();
+        listener.accept().unwrap().0.read_to_string(&mut payload).unwrap();
+        assert_eq!(payload.lines().filter(|l| l.starts_with("s ")).count(), 25);
+        assert!(payload.contains("s 24 1500000000\n"));
+    }
+
+    #[test]
+    fn failed_flushes_are_retried_over_one_connection() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        drop(listener);
+
+        let mut graphite = Graphite::new(addr).unwrap();
+        let mut core = Core::new().unwrap();
+        graphite.start(&core.handle());
+        let mut cache = CapellaCache::default();
+
+        // The first flush cannot connect, and the next one is held back while waiting to
+        // reconnect. Both keep their lines.
+        cache.add_line(&parse_line(b"a:1|g").unwrap());
+        assert!(core.run(graphite.purge_metrics(&cache.flush(1500000000))).is_err());
+        cache.add_line(&parse_line(b"b:2|g").unwrap());
+        assert!(core.run(graphite.purge_metrics(&cache.flush(1500000010))).is_err());
+        assert_eq!(graphite.connection.borrow().queued_lines, 13);
+
+        let listener = TcpListener::bind(addr).unwrap();
+        graphite.connection.borrow_mut().retry_at = None;
+        core.run(graphite.purge_metrics(&cache.flush(1500000020))).unwrap();
+        core.run(graphite.purge_metrics(&cache.flush(1500000030))).unwrap();
+        drop(graphite);
+
+        let mut payload = String::new();
+        listener.accept().unwrap().0.read_to_string(&mut payload).unwrap();
+        assert!(payload.starts_with("a 1 1500000000\n"));
+        assert!(payload.contains("b 2 1500000010\n"));
+        assert!(payload.contains("capella.graphite.queued_lines 13 1500000020\n"));
+        assert!(payload.contains("capella.graphite.retried_lines 13 1500000030\n"));
+        assert!(payload.ends_with("capella.graphite.dropped_lines 0 1500000030\n"));
+
+        // Every flush after the outage went over the same connection.
+        listener.set_nonblocking(true).unwrap();
+        assert_eq!(listener.accept().unwrap_err().kind(), ErrorKind::WouldBlock);
+    }
+
+    type Pickled = Vec<(String, (i64, f64))>;
+
+    // Split off the first `n` bytes of the data.
+    fn take<'a>(data: &mut &'a [u8], n: usize) -> &'a [u8] {
+        let (taken, rest) = data.split_at(n);
+        *data = rest;
+        taken
+    }
+
+    // Decode the pickle messages written by `make_pickle`, checking every opcode on the way.
+    fn unpickle(mut data: &[u8]) -> Vec<Pickled> {
+        let mut messages = Vec::new();
+        while !data.is_empty() {
+            let length = u32::from_be_bytes(<[u8; 4]>::try_from(take(&mut data, 4)).unwrap());
+            let mut pickle = take(&mut data, length as usize);
+            assert_eq!(take(&mut pickle, 4), &[0x80, 2, b']', b'(']);
+
+            let mut metrics = Vec::new();
+            while pickle[0] == b'X' {
+                take(&mut pickle, 1);
+                let length = u32::from_le_bytes(<[u8; 4]>::try_from(take(&mut pickle, 4)).unwrap());
+                let path = String::from_utf8(take(&mut pickle, length as usize).to_vec()).unwrap();
+
+                let timestamp = match take(&mut pickle, 1)[0] {
+                    b'J' => {
+                        i64::from(i32::from_le_bytes(<[u8; 4]>::try_from(take(&mut pickle, 4))
+                            .unwrap()))
+                    }
+                    0x8a => {
+                        assert_eq!(take(&mut pickle, 1), &[8]);
+                        i64::from_le_bytes(<[u8; 8]>::try_from(take(&mut pickle, 8)).unwrap())
+                    }
+                    op => panic!("unexpected opcode {:#x}", op),
+                };
+
+                assert_eq!(take(&mut pickle, 1), b"G");
+                let value = f64::from_be_bytes(<[u8; 8]>::try_from(take(&mut pickle, 8)).unwrap());
+                assert_eq!(take(&mut pickle, 2), &[0x86, 0x86]);
+                metrics.push((path, (timestamp, value)));
+            }
+
+            assert_eq!(pickle, b"e.");
+            messages.push(metrics);
+        }
+
+        messages
+    }
+
+    #[test]
+    fn pickle_bytes() {
+        let mut expected = vec![0, 0, 0, 30, 0x80, 2, b']', b'('];
+        expected.extend_from_slice(&[b'X', 3, 0, 0, 0, b'a', b'.', b'b']);
+        expected.extend_from_slice(&[b'J', 0x00, 0x2f, 0x68, 0x59]);
+        expected.extend_from_slice(&[b'G', 0x3f, 0xf8, 0, 0, 0, 0, 0, 0]);
+        expected.extend_from_slice(&[0x86, 0x86, b'e', b'.']);
+
+        assert_eq!(make_pickle(b"a.b 1.5 1500000000\n"), expected);
+    }
+
+    #[test]
+    fn pickle_messages() {
+        let mut lines = String::from("api;env=prod_west -2 4102444800\nnot a metric\n");
+        for i in 0..500 {
+            lines.push_str(&format!("m{} {} 1500000000\n", i, i));
+        }
+
+        let messages = unpickle(&make_pickle(lines.as_bytes()));
+        assert_eq!(messages.len(), 2);
+        assert_eq!(messages[0].len(), 500);
+        assert_eq!(messages[0][0], (String::from("api;env=prod_west"), (4102444800, -2.0)));
+        assert_eq!(messages[0][1], (String::from("m0"), (1500000000, 0.0)));
+        assert_eq!(messages[1], vec![(String::from("m499"), (1500000000, 499.0))]);
+        assert!(make_pickle(b"").is_empty());
+    }
+
+    #[test]
+    fn protocols() {
+        assert_eq!("plaintext".parse::<Protocol>().unwrap(), Protocol::Plaintext);
+        assert_eq!("pickle".parse::<Protocol>().unwrap(), Protocol::Pickle);
+        assert_eq!("udp".parse::<Protocol>().unwrap(), Protocol::Udp);
+        assert!("tcp".parse::<Protocol>().is_err());
+    }
+
+    #[test]
+    fn flush_with_pickle_and_udp() {
+        let mut core = Core::new().unwrap();
+        let mut cache = CapellaCache::default();
+        cache.add_line(&parse_line(b"temp:21|g").unwrap());
+        let snapshot = cache.flush(1500000000);
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut graphite = Graphite::new(listener.local_addr().unwrap())
+            .unwrap()
+            .with_protocol(Protocol::Pickle);
+        graphite.start(&core.handle());
+        core.run(graphite.purge_metrics(&snapshot)).unwrap();
+        drop(graphite);
+
+        let mut payload = Vec::new();
+        listener.accept().unwrap().0.read_to_end(&mut payload).unwrap();
+        let messages = unpickle(&payload);
+        assert_eq!(messages[0][0], (String::from("temp"), (1500000000, 21.0)));
+
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let mut graphite = Graphite::new(socket.local_addr().unwrap())
+            .unwrap()
+            .with_protocol(Protocol::Udp);
+        graphite.start(&core.handle());
+        core.run(graphite.purge_metrics(&snapshot)).unwrap();
+
+        let mut datagram = [0; 1400];
+        let length = socket.recv(&mut datagram).unwrap();
+        assert!(datagram[..length].starts_with(b"temp 21 1500000000\n"));
+    }
+
+    #[test]
+    fn tagged_metric_string() {
+        let graphite = Graphite::new("127.0.0.1:2003").unwrap();
+        let tags = vec![Tag {
+                            name: String::from("canary"),
+                            value: None,
+                        },
+                        Tag {
+                            name: String::from("env"),
+                            value: Some(String::from("prod")),
+                        }];
+
+        let s = graphite.make_metric_string("test", Some("count"), &tags, &1.0, "1500000000");
+        assert_eq!(s, "test.count;canary=true;env=prod 1 1500000000\n");
+    }
 
-        let send = TcpStream::connect(&self.addr, &handle)
-            .and_then(|out| ::tokio_io::io::write_all(out, buffer));
-        drop(core.run(send));
+    #[test]
+    fn tags_are_sanitized() {
+        let graphite = Graphite::new("127.0.0.1:2003").unwrap();
+        let mut cache = CapellaCache::default();
+        cache.add_line(&parse_line(b"m:1|g|#env:prod west,a;b=c:~x~y,empty:,a!b^c:d\te=f")
+            .unwrap());
 
-        cache.reset();
+        let buffer = graphite.make_buffer(&cache.flush(1500000000));
+        assert!(buffer.starts_with("m;a_b_c=d_e=f;a_b_c=_x~y;empty=true;env=prod_west \
+                                    1 1500000000\n"));
     }
 }
@@ -0,0 +1,124 @@
+//! The hll module implements a HyperLogLog sketch used to estimate the cardinality of very large
+//! sets in a fixed amount of memory.
+#![deny(missing_docs)]
+
+/// The smallest precision that is accepted.
+pub const MIN_PRECISION: u8 = 4;
+
+/// The largest precision that is accepted.
+pub const MAX_PRECISION: u8 = 16;
+
+/// A `HyperLogLog` estimates the number of distinct hashes it has seen. A precision of `p` uses
+/// `2^p` one byte registers and has a standard error of about `1.04 / sqrt(2^p)`.
+#[derive(Clone, Debug)]
+pub struct HyperLogLog {
+    precision: u8,
+    registers: Vec<u8>,
+}
+
+impl HyperLogLog {
+    /// Create a new, empty sketch. The precision is clamped to the supported range.
+    pub fn new(precision: u8) -> HyperLogLog {
+        let precision = precision.clamp(MIN_PRECISION, MAX_PRECISION);
+        HyperLogLog {
+            precision,
+            registers: vec![0; 1 << precision],
+        }
+    }
+
+    /// Return the standard error of the estimate for a given precision.
+    pub fn standard_error(precision: u8) -> f64 {
+        let precision = precision.clamp(MIN_PRECISION, MAX_PRECISION);
+        1.04 / f64::from(1u32 << precision).sqrt()
+    }
+
+    /// Add a 64 bit hash to the sketch. The hash should be uniformly distributed.
+    pub fn insert(&mut self, hash: u64) {
+        // The top bits choose the register and the rank of the remaining bits is recorded. A
+        // sentinel bit keeps the rank bounded when the remaining bits are all zero.
+        let index = (hash >> (64 - self.precision)) as usize;
+        let rest = (hash << self.precision) | (1 << (self.precision - 1));
+        let rank = rest.leading_zeros() as u8 + 1;
+
+        if rank > self.registers[index] {
+            self.registers[index] = rank;
+        }
+    }
+
+    /// Return the estimated number of distinct hashes that have been inserted.
+    pub fn cardinality(&self) -> f64 {
+        let m = self.registers.len() as f64;
+        let alpha = match self.registers.len() {
+            16 => 0.673,
+            32 => 0.697,
+            64 => 0.709,
+            _ => 0.7213 / (1.0 + 1.079 / m),
+        };
+
+        let sum: f64 = self.registers.iter().map(|r| 2f64.powi(-i32::from(*r))).sum();
+        let estimate = alpha * m * m / sum;
+
+        // Use linear counting while many registers are still empty, where it is more accurate.
+        let zeros = self.registers.iter().filter(|r| **r == 0).count();
+        if estimate <= 2.5 * m && zeros > 0 {
+            return m * (m / zeros as f64).ln();
+        }
+
+        estimate
+    }
+
+    /// Remove every hash from the sketch.
+    pub fn clear(&mut self) {
+        for r in &mut self.registers {
+            *r = 0;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::hash_map::DefaultHasher;
+    use std::hash::{Hash, Hasher};
+
+    use super::HyperLogLog;
+
+    fn hash(i: u64) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        i.hash(&mut hasher);
+        hasher.finish()
+    }
+
+    #[test]
+    fn empty_sketch() {
+        let hll = HyperLogLog::new(14);
+        assert_eq!(hll.cardinality(), 0.0);
+    }
+
+    #[test]
+    fn duplicates_are_ignored() {
+        let mut hll = HyperLogLog::new(14);
+        for _ in 0..1000 {
+            hll.insert(hash(1));
+            hll.insert(hash(2));
+        }
+        assert!((hll.cardinality() - 2.0).abs() < 0.01);
+
+        hll.clear();
+        assert_eq!(hll.cardinality(), 0.0);
+    }
+
+    #[test]
+    fn estimate_is_within_error() {
+        for &(precision, count) in &[(10, 500), (12, 50_000), (14, 1_000_000)] {
+            let mut hll = HyperLogLog::new(precision);
+            for i in 0..count {
+                hll.insert(hash(i));
+            }
+
+            // Allow three standard errors either way.
+            let error = (hll.cardinality() - count as f64).abs() / count as f64;
+            assert!(error < 3.0 * HyperLogLog::standard_error(precision),
+                    "precision {} estimated {} for {}", precision, hll.cardinality(), count);
+        }
+    }
+}
@@ -0,0 +1,302 @@
+//! The influx module is a backend that writes metrics to InfluxDB using the line protocol.
+#![deny(missing_docs)]
+
+use std::io;
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::time::Duration;
+
+use futures::{future, Future};
+
+use tokio_core::net::TcpStream;
+use tokio_core::reactor::Handle;
+
+use tokio_io::io::write_all;
+
+use backend::{not_started, post_http, send_datagrams, with_timeout, Backend, Flush,
+              DEFAULT_CONNECT_TIMEOUT_SECS, DEFAULT_WRITE_TIMEOUT_SECS};
+
+use cache::{backend_tag, source_tag};
+
+use parse::Tag;
+
+use snapshot::FlushSnapshot;
+
+const CAPELLA_MEASUREMENT: &str = "capella";
+const VALUE_FIELD: &str = "value";
+const RATE_FIELD: &str = "rate";
+const COUNT_FIELD: &str = "count";
+const DEFAULT_WRITE_PATH: &str = "/write";
+
+/// `Transport` describes how the line protocol is delivered to InfluxDB.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Transport {
+    /// Newline delimited lines over a TCP connection.
+    Tcp,
+
+    /// Lines packed into UDP datagrams.
+    Udp,
+
+    /// A `POST` to the HTTP write endpoint. The host header and the path, including any query
+    /// string such as `?db=capella`, are kept here.
+    Http {
+        /// The value of the `Host` header.
+        host: String,
+
+        /// The request path and query string.
+        path: String,
+    },
+}
+
+/// The backend to an InfluxDB server.
+#[derive(Debug)]
+pub struct Influx {
+    addr: SocketAddr,
+    transport: Transport,
+    connect_timeout: Duration,
+    write_timeout: Duration,
+    handle: Option<Handle>,
+}
+
+impl Influx {
+    /// Construct a new influx instance from a connection string such as `udp://127.0.0.1:8089`,
+    /// `tcp://127.0.0.1:8094` or `http://127.0.0.1:8086/write?db=capella`.
+    pub fn new(conn: &str) -> io::Result<Influx> {
+        let invalid = || io::Error::new(io::ErrorKind::InvalidInput, "invalid influx connection");
+
+        let mut parts = conn.splitn(2, "://");
+        let scheme = parts.next().ok_or_else(invalid)?;
+        let rest = parts.next().ok_or_else(invalid)?;
+        let (authority, path) = match rest.find('/') {
+            Some(i) => (&rest[..i], &rest[i..]),
+            None => (rest, DEFAULT_WRITE_PATH),
+        };
+
+        let transport = match scheme {
+            "tcp" => Transport::Tcp,
+            "udp" => Transport::Udp,
+            "http" => {
+                Transport::Http {
+                    host: String::from(authority),
+                    path: String::from(path),
+                }
+            }
+            _ => return Err(invalid()),
+        };
+
+        Ok(Influx {
+            addr: authority.to_socket_addrs()?.next().ok_or_else(invalid)?,
+            transport,
+            connect_timeout: Duration::from_secs(DEFAULT_CONNECT_TIMEOUT_SECS),
+            write_timeout: Duration::from_secs(DEFAULT_WRITE_TIMEOUT_SECS),
+            handle: None,
+        })
+    }
+
+    /// Set how long a flush may take to connect to InfluxDB, and then how long it may take to
+    /// write the lines and, over HTTP, to read the response. Both default to 5 seconds.
+    pub fn with_timeouts(mut self, connect_timeout: Duration, write_timeout: Duration) -> Influx {
+        self.connect_timeout = connect_timeout;
+        self.write_timeout = write_timeout;
+        self
+    }
+
+    // Build the line protocol for everything in the snapshot. Every timer becomes a single line
+    // with one field per statistic.
+    fn make_lines(&self, snapshot: &FlushSnapshot) -> String {
+        // Line protocol timestamps default to nanosecond precision.
+        let timestamp = &(snapshot.timestamp() * 1_000_000_000).to_string();
+        let mut buffer = String::new();
+
+        // Both iterators walk the same counters in the same order.
+        for ((k, v), (_, rate)) in snapshot.counters_iter().zip(snapshot.counter_rates_iter()) {
+            push_line(&mut buffer, k.name(), k.tags(), &[(VALUE_FIELD, *v), (RATE_FIELD, rate)],
+                      timestamp);
+        }
+
+        for (k, v) in snapshot.gauges_iter() {
+            push_line(&mut buffer, k.name(), k.tags(), &[(VALUE_FIELD, *v)], timestamp);
+        }
+
+        for (k, stats) in snapshot.timer_data_iter() {
+            let fields: Vec<(&str, f64)> = stats.iter().map(|s| (s.0.as_str(), s.1)).collect();
+            push_line(&mut buffer, k.name(), k.tags(), &fields, timestamp);
+        }
+
+        for (k, v) in snapshot.sets_iter() {
+            let fields = [(COUNT_FIELD, *v)];
+            push_line(&mut buffer, k.name(), k.tags(), &fields, timestamp);
+        }
+
+        let reasons: Vec<(String, f64)> = snapshot.bad_metrics_iter()
+            .map(|(reason, count)| (format!("bad_metrics_{}", reason), *count as f64))
+            .collect();
+        let mut internal = vec![("total_metrics", snapshot.total_metrics()),
+                                ("bad_metrics", snapshot.total_bad_metrics())];
+        internal.extend(reasons.iter().map(|r| (r.0.as_str(), r.1)));
+        push_line(&mut buffer, CAPELLA_MEASUREMENT, &[], &internal, timestamp);
+        for (source, count) in snapshot.bad_sources_iter() {
+            push_line(&mut buffer, CAPELLA_MEASUREMENT, &[source_tag(source)],
+                      &[("bad_metrics", *count as f64)], timestamp);
+        }
+        for (backend, stats) in snapshot.backend_stats_iter() {
+            let fields = [("flushes", stats.flushes as f64),
+                          ("flush_errors", stats.errors as f64),
+                          ("flush_latency_ms", stats.max_latency.as_secs_f64() * 1000.0)];
+            push_line(&mut buffer, CAPELLA_MEASUREMENT, &[backend_tag(backend)], &fields,
+                      timestamp);
+        }
+
+        buffer
+    }
+
+    // Deliver the lines using the configured transport without blocking the event loop.
+    fn send(&self, handle: &Handle, lines: String) -> Flush {
+        let addr = self.addr;
+        let (connect_timeout, write_timeout) = (self.connect_timeout, self.write_timeout);
+        let inner = handle.clone();
+
+        match self.transport {
+            Transport::Tcp => {
+                let connect = TcpStream::connect(&addr, handle);
+                Box::new(with_timeout(connect, connect_timeout, handle, "connecting to influx")
+                    .and_then(move |out| {
+                        with_timeout(write_all(out, lines), write_timeout, &inner,
+                                     "writing to influx")
+                    })
+                    .map(|_| ()))
+            }
+            Transport::Udp => send_datagrams(addr, lines.as_bytes(), write_timeout, handle),
+            Transport::Http { ref host, ref path } => {
+                post_http(addr, host, path, "text/plain", &lines,
+                          (connect_timeout, write_timeout), handle)
+            }
+        }
+    }
+}
+
+impl Backend for Influx {
+    fn name(&self) -> &'static str {
+        "influx"
+    }
+
+    fn start(&mut self, handle: &Handle) {
+        self.handle = Some(handle.clone());
+    }
+
+    fn purge_metrics(&self, snapshot: &FlushSnapshot) -> Flush {
+        let lines = self.make_lines(snapshot);
+
+        match self.handle {
+            Some(ref handle) => self.send(handle, lines),
+            None => Box::new(future::err(not_started())),
+        }
+    }
+}
+
+// Append a single line of the form `measurement,tag=value field=value timestamp`.
+fn push_line(buffer: &mut String,
+             measurement: &str,
+             tags: &[Tag],
+             fields: &[(&str, f64)],
+             timestamp: &str) {
+    buffer.push_str(&escape(measurement, &[',', ' ']));
+    for tag in tags {
+        let value = tag.value.as_ref().map_or("true", |v| v.as_str());
+        buffer.push(',');
+        buffer.push_str(&escape(&tag.name, &[',', '=', ' ']));
+        buffer.push('=');
+        buffer.push_str(&escape(value, &[',', '=', ' ']));
+    }
+
+    for (i, &(name, value)) in fields.iter().enumerate() {
+        buffer.push(if i == 0 { ' ' } else { ',' });
+        buffer.push_str(&escape(name, &[',', '=', ' ']));
+        buffer.push('=');
+        buffer.push_str(&value.to_string());
+    }
+
+    buffer.push(' ');
+    buffer.push_str(timestamp);
+    buffer.push('\n');
+}
+
+fn escape(s: &str, special: &[char]) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        if c == '\\' || special.contains(&c) {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+
+    use std::io::ErrorKind;
+    use std::net::TcpListener;
+    use std::time::Duration;
+
+    use tokio_core::reactor::Core;
+
+    use super::{Influx, Transport};
+    use backend::Backend;
+    use cache::CapellaCache;
+    use parse::{Metric, MetricType};
+
+    fn make_metric(name: &str, value: f64, metric_type: MetricType) -> Metric<'_> {
+        let mut m = Metric::new();
+        m.name = name;
+        m.value = value;
+        m.metric_type = metric_type;
+        m
+    }
+
+    #[test]
+    fn connection_strings() {
+        assert_eq!(Influx::new("udp://127.0.0.1:8089").unwrap().transport, Transport::Udp);
+        assert_eq!(Influx::new("tcp://127.0.0.1:8094").unwrap().transport, Transport::Tcp);
+        assert_eq!(Influx::new("http://127.0.0.1:8086/write?db=capella").unwrap().transport,
+                   Transport::Http {
+                       host: String::from("127.0.0.1:8086"),
+                       path: String::from("/write?db=capella"),
+                   });
+        assert!(Influx::new("127.0.0.1:8086").is_err());
+        assert!(Influx::new("ftp://127.0.0.1:21").is_err());
+    }
+
+    #[test]
+    fn timer_is_one_line() {
+        let influx = Influx::new("udp://127.0.0.1:8089").unwrap();
+        let mut cache = CapellaCache::default();
+
+        let mut timer = make_metric("api req", 2.0, MetricType::Timer);
+        timer.raw_tags = "env:prod west";
+        cache.add_metric(&timer);
+
+        let lines = influx.make_lines(&cache.flush(1500000000));
+        let mut lines = lines.lines();
+        assert_eq!(lines.next().unwrap(),
+                   "api\\ req,env=prod\\ west min=2,max=2,count=1,count_ps=0.1,average=2,std_dev=0,median=2,\
+                    upper_95=2,mean_95=2,sum_95=2 1500000000000000000");
+        assert_eq!(lines.next().unwrap(),
+                   "capella total_metrics=1,bad_metrics=0 1500000000000000000");
+    }
+
+    #[test]
+    fn unanswered_writes_time_out() {
+        // The listener accepts the connection but never sends a response.
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let conn = format!("http://{}/write?db=capella", listener.local_addr().unwrap());
+        let mut influx = Influx::new(&conn)
+            .unwrap()
+            .with_timeouts(Duration::from_secs(5), Duration::from_millis(50));
+
+        let mut core = Core::new().unwrap();
+        influx.start(&core.handle());
+        let res = core.run(influx.purge_metrics(&CapellaCache::default().flush(0)));
+        assert_eq!(res.unwrap_err().kind(), ErrorKind::TimedOut);
+    }
+}
@@ -0,0 +1,27 @@
+//! capella is an asynchronous StatsD server. The binary reads its configuration from the
+//! environment, while the library exposes the parser, cache and backends it is built from.
+#[macro_use]
+extern crate log;
+
+extern crate bytes;
+extern crate chrono;
+extern crate futures;
+extern crate tokio_codec;
+extern crate tokio_core;
+extern crate tokio_io;
+extern crate tokio_timer;
+extern crate tokio_uds;
+
+pub mod backend;
+pub mod cache;
+pub mod console;
+pub mod error;
+pub mod graphite;
+pub mod hll;
+pub mod influx;
+pub mod parse;
+pub mod prometheus;
+pub mod server;
+pub mod sketch;
+pub mod snapshot;
+pub mod webhook;
@@ -1,30 +1,28 @@
 #[macro_use]
-extern crate lazy_static;
-#[macro_use]
 extern crate log;
 
-extern crate chrono;
+extern crate capella;
 extern crate dotenv;
 extern crate env_logger;
-extern crate futures;
-extern crate regex;
-extern crate tokio_core;
-extern crate tokio_io;
-extern crate tokio_timer;
-
-pub mod backend;
-pub mod cache;
-pub mod console;
-pub mod error;
-pub mod graphite;
-pub mod parse;
-pub mod server;
 
+use std::collections::HashSet;
 use std::env;
+use std::time::Duration;
+
+use capella::backend::{Backend, DEFAULT_CONNECT_TIMEOUT_SECS, DEFAULT_WRITE_TIMEOUT_SECS};
+
+use capella::console::Console;
+
+use capella::graphite::{Graphite, Protocol, DEFAULT_COUNT_PREFIX, DEFAULT_RATE_PREFIX,
+                        DEFAULT_SPILL_MAX_BYTES};
 
-use graphite::Graphite;
+use capella::influx::Influx;
 
-use server::start_udp_server;
+use capella::prometheus::Prometheus;
+
+use capella::server::start_udp_server;
+
+use capella::webhook::Webhook;
 
 // Print the current environment information.
 fn print_setup() {
@@ -36,15 +34,80 @@ fn print_setup() {
     }
 }
 
+// Build the backend with the given name from its environment variables.
+fn make_backend(name: &str) -> Box<dyn Backend> {
+    match name {
+        "graphite" => {
+            let graphite_conn = env::var("CAPELLA_GRAPHITE_CONNECTION").unwrap();
+            let mut graphite = Graphite::new(graphite_conn.as_str()).unwrap();
+            let rate = env::var("CAPELLA_GRAPHITE_RATE_PREFIX")
+                .unwrap_or_else(|_| String::from(DEFAULT_RATE_PREFIX));
+            let count = env::var("CAPELLA_GRAPHITE_COUNT_PREFIX")
+                .unwrap_or_else(|_| String::from(DEFAULT_COUNT_PREFIX));
+            graphite = graphite.with_counter_prefixes(&rate, &count);
+            let connect = env::var("CAPELLA_GRAPHITE_CONNECT_TIMEOUT")
+                .map_or(DEFAULT_CONNECT_TIMEOUT_SECS, |t| t.parse().unwrap());
+            let write = env::var("CAPELLA_GRAPHITE_WRITE_TIMEOUT")
+                .map_or(DEFAULT_WRITE_TIMEOUT_SECS, |t| t.parse().unwrap());
+            graphite = graphite.with_timeouts(Duration::from_secs(connect),
+                                              Duration::from_secs(write));
+            if let Ok(protocol) = env::var("CAPELLA_GRAPHITE_PROTOCOL") {
+                graphite = graphite.with_protocol(protocol.parse::<Protocol>().unwrap());
+            }
+            if let Ok(lines) = env::var("CAPELLA_GRAPHITE_QUEUE_LINES") {
+                graphite = graphite.with_queue_limit(lines.parse().unwrap());
+            }
+            if let Ok(path) = env::var("CAPELLA_GRAPHITE_SPILL_PATH") {
+                let max_bytes = env::var("CAPELLA_GRAPHITE_SPILL_MAX_BYTES")
+                    .map_or(DEFAULT_SPILL_MAX_BYTES, |b| b.parse().unwrap());
+                graphite = graphite.with_spill(path, max_bytes);
+            }
+            Box::new(graphite)
+        }
+        "influx" => {
+            let influx_conn = env::var("CAPELLA_INFLUX_CONNECTION").unwrap();
+            Box::new(Influx::new(influx_conn.as_str()).unwrap())
+        }
+        "prometheus" => {
+            let prometheus_addr = env::var("CAPELLA_PROMETHEUS_LISTENER").unwrap();
+            Box::new(Prometheus::new(prometheus_addr.as_str()).unwrap())
+        }
+        "webhook" => {
+            let webhook_url = env::var("CAPELLA_WEBHOOK_URL").unwrap();
+            let connect = env::var("CAPELLA_WEBHOOK_CONNECT_TIMEOUT")
+                .map_or(DEFAULT_CONNECT_TIMEOUT_SECS, |t| t.parse().unwrap());
+            let write = env::var("CAPELLA_WEBHOOK_WRITE_TIMEOUT")
+                .map_or(DEFAULT_WRITE_TIMEOUT_SECS, |t| t.parse().unwrap());
+            let webhook = Webhook::new(webhook_url.as_str())
+                .unwrap()
+                .with_timeouts(Duration::from_secs(connect), Duration::from_secs(write));
+            Box::new(webhook)
+        }
+        "console" => Box::new(Console),
+        other => panic!("unknown backend: {}", other),
+    }
+}
+
 fn main() {
     // Setup our environment.
     dotenv::from_filename("capella.env").ok();
     env_logger::init().unwrap();
 
-    let graphite_conn = env::var("CAPELLA_GRAPHITE_CONNECTION").unwrap();
-    let backend = Graphite::new(graphite_conn.as_str()).unwrap();
-
     print_setup();
 
-    start_udp_server(backend);
+    // Graphite remains the default backend when none is configured, and a single backend can
+    // still be named with `CAPELLA_BACKEND`.
+    let names = env::var("CAPELLA_BACKENDS")
+        .or_else(|_| env::var("CAPELLA_BACKEND"))
+        .unwrap_or_else(|_| String::from("graphite"));
+    let mut seen = HashSet::new();
+    let backends = names.split(',')
+        .map(str::trim)
+        .map(|name| {
+            assert!(seen.insert(name), "the {} backend is listed more than once", name);
+            make_backend(name)
+        })
+        .collect();
+
+    start_udp_server(backends);
 }
@@ -1,12 +1,9 @@
 //! The parse module is responsible for parsing events published by clients.
 #![deny(missing_docs)]
 
-use regex::Regex;
-
-use std::rc::Rc;
 use std::str::{self, FromStr};
 
-use error::{Error, CapellaResult};
+use error::{Error, CapellaResult, Field};
 
 /// `MetricType` defines what kind of metric was parsed from a client.
 #[derive(Clone, Debug, Eq, PartialEq)]
@@ -22,106 +19,570 @@ pub enum MetricType {
 
     /// A timer can collect different timing events over a flush duration.
     Timer,
+
+    /// A histogram is aggregated like a timer, but its values can be in any unit.
+    Histogram,
+
+    /// A distribution is kept as a mergeable quantile sketch.
+    Distribution,
 }
 
 impl FromStr for MetricType {
     type Err = Error;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        match s {
-            "c" => Ok(MetricType::Counter),
-            "g" => Ok(MetricType::Gauge),
-            "ms" => Ok(MetricType::Timer),
-            "s" => Ok(MetricType::Set),
-            _ => Err(Error::Parse),
+        parse_type(s.as_bytes())
+    }
+}
+
+/// A `Tag` is a single DogStatsD style tag attached to a metric. Tags are either a bare name or a
+/// `name:value` pair.
+#[derive(Clone, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub struct Tag {
+    /// The name of the tag.
+    pub name: String,
+
+    /// The optional value of the tag.
+    pub value: Option<String>,
+}
+
+impl FromStr for Tag {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.splitn(2, ':');
+        // There is always at least one element from `splitn`.
+        let name = parts.next().unwrap();
+        if name.is_empty() {
+            return Err(Error::InvalidTag { offset: 0 });
         }
+
+        Ok(Tag {
+            name: String::from(name),
+            value: parts.next().map(String::from),
+        })
     }
 }
 
-/// A `Metric` defines a published client event.
-#[derive(Debug, PartialEq)]
-pub struct Metric {
+/// A `Metric` defines a published client event. It borrows its name, set member and tags from
+/// the packet it was parsed from, so parsing a metric does not allocate.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Metric<'a> {
     /// The name of the metric.
-    pub name: Rc<String>,
+    pub name: &'a str,
 
-    /// The float value of a metric.
+    /// The float value of a metric. This is zero for sets, which use `member` instead.
     pub value: f64,
 
+    /// The member added by a set metric. Sets accept any non-empty string as a member.
+    pub member: Option<&'a str>,
+
     /// The type of metric that was sent.
     pub metric_type: MetricType,
 
     /// An optional sample rate used in some calculations.
     pub sample_rate: Option<f64>,
+
+    /// The comma separated tags sent along with the metric, exactly as the client sent them. This
+    /// is empty for a metric without tags.
+    pub raw_tags: &'a str,
+
+    /// Whether the value was sent with an explicit `+` or `-` sign. A signed gauge adjusts the
+    /// current value instead of replacing it.
+    pub explicit_sign: bool,
 }
 
-impl Metric {
+impl<'a> Metric<'a> {
     /// Create a new metric.
-    pub fn new() -> Metric {
+    pub fn new() -> Metric<'a> {
         Metric {
-            name: Rc::new(String::new()),
+            name: "",
             value: 0.0,
+            member: None,
             metric_type: MetricType::Counter,
             sample_rate: None,
+            raw_tags: "",
+            explicit_sign: false,
         }
     }
+
+    /// Return the tags of the metric in the order the client sent them. A tag that is not valid
+    /// is skipped, which can only happen to a metric that was not built by the parser.
+    pub fn tags(&self) -> impl Iterator<Item = Tag> + 'a {
+        self.raw_tags.split(',').filter_map(|t| t.parse::<Tag>().ok())
+    }
 }
 
-/// The `parse_metric` function trys to break down a single UDP packet into a single metric.
-pub fn parse_metric(packet: &[u8]) -> CapellaResult<Metric> {
-    lazy_static! {
-        static ref PATTERN: Regex = Regex::new(r"(?x)
-            \A(?P<name>[\w\.]+):
-            ((?P<sign>\-|\+))?
-            (?P<val>([0-9]*[.])?[0-9]+)
-            \|(?P<type>\w+)
-            (\|@(?P<rate>\d+\.\d+))?\z").unwrap();
+impl<'a> Default for Metric<'a> {
+    fn default() -> Metric<'a> {
+        Metric::new()
     }
+}
+
+/// `ServiceCheckStatus` is the state reported by a DogStatsD service check.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ServiceCheckStatus {
+    /// The service is healthy.
+    Ok,
+
+    /// The service is degraded.
+    Warning,
+
+    /// The service is failing.
+    Critical,
 
-    if let Ok(val) = str::from_utf8(packet) {
-        if !PATTERN.is_match(val) {
-            trace!("UDP packet did not match the pattern");
-            return Err(Error::Parse);
+    /// The state of the service could not be determined.
+    Unknown,
+}
+
+impl ServiceCheckStatus {
+    /// Return the numeric code of the status as it is sent by clients.
+    pub fn code(self) -> u8 {
+        match self {
+            ServiceCheckStatus::Ok => 0,
+            ServiceCheckStatus::Warning => 1,
+            ServiceCheckStatus::Critical => 2,
+            ServiceCheckStatus::Unknown => 3,
         }
+    }
+}
+
+impl FromStr for ServiceCheckStatus {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        parse_status(s.as_bytes())
+    }
+}
+
+/// An `Event` is a DogStatsD event such as a deploy or an alert. Events are not aggregated, they
+/// are kept until the next flush and handed to the backends as they were sent.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Event {
+    /// The title of the event.
+    pub title: String,
+
+    /// The text of the event. Escaped newlines have been replaced with real ones.
+    pub text: String,
+
+    /// The Unix time at which the event happened, if the client sent one.
+    pub timestamp: Option<i64>,
+
+    /// The host the event is about.
+    pub hostname: Option<String>,
+
+    /// A key used to group related events together.
+    pub aggregation_key: Option<String>,
+
+    /// The priority of the event, usually `normal` or `low`.
+    pub priority: Option<String>,
+
+    /// The name of the source of the event.
+    pub source_type: Option<String>,
+
+    /// The kind of alert, usually `error`, `warning`, `info` or `success`.
+    pub alert_type: Option<String>,
+
+    /// The tags sent along with the event.
+    pub tags: Vec<Tag>,
+}
+
+/// A `ServiceCheck` reports the status of a service.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ServiceCheck {
+    /// The name of the check.
+    pub name: String,
+
+    /// The status of the service.
+    pub status: ServiceCheckStatus,
+
+    /// The Unix time at which the check ran, if the client sent one.
+    pub timestamp: Option<i64>,
+
+    /// The host the check ran on.
+    pub hostname: Option<String>,
+
+    /// A message describing the status. Escaped newlines have been replaced with real ones.
+    pub message: Option<String>,
+
+    /// The tags sent along with the check.
+    pub tags: Vec<Tag>,
+}
+
+/// `Line` is what a single line of a packet can hold.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Line<'a> {
+    /// One or more metrics, which is more than one when values were packed into the line.
+    Metrics(Vec<Metric<'a>>),
+
+    /// A DogStatsD event.
+    Event(Event),
+
+    /// A DogStatsD service check.
+    ServiceCheck(ServiceCheck),
+}
+
+/// The `parse_line` function works out what kind of line was sent and parses it.
+pub fn parse_line(packet: &[u8]) -> CapellaResult<Line<'_>> {
+    if packet.starts_with(b"_e{") {
+        parse_event(packet).map(Line::Event)
+    } else if packet.starts_with(b"_sc|") {
+        parse_service_check(packet).map(Line::ServiceCheck)
     } else {
-        trace!("UDP packet was invalid UTF-8");
-        return Err(Error::Parse);
+        parse_metrics(packet).map(Line::Metrics)
+    }
+}
+
+/// The `parse_event` function parses a DogStatsD event of the form
+/// `_e{title.length,text.length}:title|text|d:timestamp|h:hostname|#tags`. The lengths are in
+/// bytes, and every field after the text is optional.
+pub fn parse_event(packet: &[u8]) -> CapellaResult<Event> {
+    let start = "_e{".len();
+    let bad_lengths = Error::Malformed { field: Field::Lengths, offset: start };
+    let close = find(packet, b"}:").ok_or_else(|| bad_lengths.clone())?;
+    let lengths = &packet[start..close];
+    let comma = lengths.iter().position(|c| *c == b',').ok_or(bad_lengths)?;
+    let title_len = parse_length(&lengths[..comma]).map_err(|e| e.shift(start))?;
+    let text_len = parse_length(&lengths[comma + 1..]).map_err(|e| e.shift(start + comma + 1))?;
+
+    // The text has to follow the title, so the title has to fit in the line with room for a `|`.
+    let title_start = close + "}:".len();
+    let title_end = title_start.saturating_add(title_len);
+    if title_len == 0 || title_end >= packet.len() || packet[title_end] != b'|' {
+        return Err(Error::Malformed { field: Field::Title, offset: title_start });
+    }
+    let text_start = title_end + 1;
+    let text_end = text_start.saturating_add(text_len);
+    if text_end > packet.len() {
+        return Err(Error::Malformed { field: Field::Text, offset: text_start });
+    }
+
+    // The sent lengths do not have to land on character boundaries, in which case the title or
+    // the text is not valid UTF-8.
+    let title = to_str(&packet[title_start..title_end], Field::Title, title_start)?;
+    let text = to_str(&packet[text_start..text_end], Field::Text, text_start)?;
+    let rest = to_str(&packet[text_end..], Field::Optional, text_end)?;
+
+    let mut event = Event {
+        title: unescape_newlines(title),
+        text: unescape_newlines(text),
+        ..Event::default()
+    };
+
+    for (offset, field) in optional_fields(rest, text_end)? {
+        match field.split_at(2) {
+            ("d:", timestamp) => event.timestamp = Some(parse_timestamp(timestamp, offset)?),
+            ("h:", hostname) => event.hostname = Some(String::from(hostname)),
+            ("k:", key) => event.aggregation_key = Some(String::from(key)),
+            ("p:", priority) => event.priority = Some(String::from(priority)),
+            ("s:", source) => event.source_type = Some(String::from(source)),
+            ("t:", alert) => event.alert_type = Some(String::from(alert)),
+            _ if field.starts_with('#') => event.tags = parse_tags(&field[1..], offset + 1)?,
+            _ => return Err(Error::Malformed { field: Field::Optional, offset }),
+        }
+    }
+
+    Ok(event)
+}
+
+/// The `parse_service_check` function parses a DogStatsD service check of the form
+/// `_sc|name|status|d:timestamp|h:hostname|#tags|m:message`. The message must come last since it
+/// may contain any character.
+pub fn parse_service_check(packet: &[u8]) -> CapellaResult<ServiceCheck> {
+    let start = "_sc|".len();
+
+    // Split off the message first so that a `|` inside of it is not taken for a field.
+    let (end, message) = match find(&packet[start..], b"|m:") {
+        Some(i) => {
+            let message_start = start + i + "|m:".len();
+            let message = to_str(&packet[message_start..], Field::Message, message_start)?;
+            (start + i, Some(unescape_newlines(message)))
+        }
+        None => (packet.len(), None),
+    };
+
+    let body = &packet[start..end];
+    let name_end = body.iter().position(|c| *c == b'|').unwrap_or(body.len());
+    if name_end == 0 {
+        return Err(Error::InvalidName { offset: start });
+    }
+    let name = to_str(&body[..name_end], Field::Name, start)?;
+    if name_end == body.len() {
+        return Err(Error::Malformed { field: Field::Status, offset: start + name_end });
+    }
+
+    let status_start = start + name_end + 1;
+    let status = &packet[status_start..end];
+    let status_end = status.iter().position(|c| *c == b'|').unwrap_or(status.len());
+    let status = parse_status(&status[..status_end]).map_err(|e| e.shift(status_start))?;
+
+    let mut check = ServiceCheck {
+        name: String::from(name),
+        status,
+        timestamp: None,
+        hostname: None,
+        message,
+        tags: Vec::new(),
+    };
+
+    let fields_start = status_start + status_end;
+    let fields = to_str(&packet[fields_start..end], Field::Optional, fields_start)?;
+    for (offset, field) in optional_fields(fields, fields_start)? {
+        match field.split_at(2) {
+            ("d:", timestamp) => check.timestamp = Some(parse_timestamp(timestamp, offset)?),
+            ("h:", hostname) => check.hostname = Some(String::from(hostname)),
+            _ if field.starts_with('#') => check.tags = parse_tags(&field[1..], offset + 1)?,
+            _ => return Err(Error::Malformed { field: Field::Optional, offset }),
+        }
+    }
+
+    Ok(check)
+}
+
+// Split the `|` separated fields that follow an event or service check, along with the offset of
+// each one in the line given the offset of `rest`. Every field needs at least a two byte prefix
+// such as `h:` or a `#` followed by a tag.
+fn optional_fields(rest: &str, offset: usize) -> CapellaResult<Vec<(usize, &str)>> {
+    if rest.is_empty() {
+        return Ok(Vec::new());
+    }
+    if !rest.starts_with('|') {
+        return Err(Error::Malformed { field: Field::Optional, offset });
+    }
+
+    let mut fields = Vec::new();
+    let mut offset = offset + 1;
+    for field in rest[1..].split('|') {
+        if field.len() < 2 || !field.is_char_boundary(2) {
+            return Err(Error::Malformed { field: Field::Optional, offset });
+        }
+        fields.push((offset, field));
+        offset += field.len() + 1;
+    }
+
+    Ok(fields)
+}
+
+// Parse the tags of an event or service check, which start at `offset` in the line.
+fn parse_tags(tags: &str, offset: usize) -> CapellaResult<Vec<Tag>> {
+    let mut offset = offset;
+    let mut parsed = Vec::new();
+    for tag in tags.split(',') {
+        parsed.push(tag.parse::<Tag>().map_err(|e| e.shift(offset))?);
+        offset += tag.len() + 1;
+    }
+
+    Ok(parsed)
+}
+
+fn parse_timestamp(field: &str, offset: usize) -> CapellaResult<i64> {
+    // The offset is that of the whole field, which starts with `d:`.
+    let offset = offset + "d:".len();
+    field.parse::<i64>().map_err(|_| Error::InvalidValue { field: Field::Timestamp, offset })
+}
+
+fn parse_length(length: &[u8]) -> CapellaResult<usize> {
+    str::from_utf8(length)
+        .ok()
+        .and_then(|l| l.parse::<usize>().ok())
+        .ok_or(Error::InvalidValue { field: Field::Lengths, offset: 0 })
+}
+
+fn parse_status(status: &[u8]) -> CapellaResult<ServiceCheckStatus> {
+    match status {
+        b"0" => Ok(ServiceCheckStatus::Ok),
+        b"1" => Ok(ServiceCheckStatus::Warning),
+        b"2" => Ok(ServiceCheckStatus::Critical),
+        b"3" => Ok(ServiceCheckStatus::Unknown),
+        _ => Err(Error::InvalidValue { field: Field::Status, offset: 0 }),
     }
+}
 
-    // We know this matched before so it is safe to unwrap.
-    let caps = PATTERN.captures(str::from_utf8(packet).unwrap()).unwrap();
+// Clients escape newlines in event text and service check messages since a real one would end the
+// line.
+fn unescape_newlines(s: &str) -> String {
+    s.replace("\\n", "\n")
+}
 
-    let mut metric = Metric::new();
-    // These are required to match.
-    let name = caps.name("name").unwrap().as_str();
-    let value = caps.name("val").unwrap().as_str().parse::<f64>().map_err(Error::from)?;
-    let metric_type = caps.name("type").unwrap().as_str();
+/// The `parse_metrics` function breaks a single line down into its metrics. A line usually holds
+/// one value, but clients may pack several values into one line, such as `latency:12:15:9|ms`.
+/// Every packed value becomes its own metric sharing the name, type, sample rate and tags.
+///
+/// A line has the form `name:value|type|@rate|#tags`, where the sample rate and the tags are
+/// optional. The line is scanned byte by byte and only the parts that are kept as strings are
+/// checked to be UTF-8.
+pub fn parse_metrics(packet: &[u8]) -> CapellaResult<Vec<Metric<'_>>> {
+    // Names cannot hold a colon, so the first one ends the name.
+    let missing = |field| Error::Malformed { field, offset: packet.len() };
+    let colon = packet.iter().position(|c| *c == b':').ok_or_else(|| missing(Field::Value))?;
+    let name = parse_name(&packet[..colon])?;
+
+    let values_start = colon + 1;
+    let rest = &packet[values_start..];
+    let pipe = rest.iter().position(|c| *c == b'|').ok_or_else(|| missing(Field::Type))?;
+    let values = &rest[..pipe];
+    if values.is_empty() {
+        return Err(Error::Malformed { field: Field::Value, offset: values_start });
+    }
 
-    metric.name = Rc::new(String::from(name));
-    metric.value = value;
-    metric.metric_type = metric_type.parse::<MetricType>()?;
+    // Everything but the value is shared by the metrics packed into the line.
+    let mut offset = values_start + pipe + 1;
+    let mut fields = rest[pipe + 1..].split(|c| *c == b'|');
+    let mut template = Metric::new();
+    template.name = name;
+    // There is always at least one element from `split`.
+    let metric_type = fields.next().unwrap();
+    template.metric_type = parse_type(metric_type).map_err(|e| e.shift(offset))?;
+    offset += metric_type.len() + 1;
+
+    // The sample rate has to come before the tags.
+    let mut field = fields.next();
+    if let Some(rate) = field.and_then(|f| f.strip_prefix(b"@")) {
+        template.sample_rate = Some(parse_rate(rate).map_err(|e| e.shift(offset + 1))?);
+        offset += rate.len() + 2;
+        field = fields.next();
+    }
+    if let Some(tags) = field.and_then(|f| f.strip_prefix(b"#")) {
+        template.raw_tags = parse_raw_tags(tags).map_err(|e| e.shift(offset + 1))?;
+        offset += tags.len() + 2;
+        field = fields.next();
+    }
+    if field.is_some() {
+        return Err(Error::Malformed { field: Field::Optional, offset });
+    }
 
-    // Now see if there were optional values added in.
-    // Counters cannot be decremented, so only do so if the metric is not a counter.
-    if let Some(sign) = caps.name("sign") {
-        let s = sign.as_str();
-        if s == "-" && metric.metric_type != MetricType::Counter {
-            metric.value *= -1.0;
+    // Set members such as `10.0.0.1:8080` may hold a colon, so sets are never packed.
+    let packed = template.metric_type != MetricType::Set;
+    let mut metrics = Vec::new();
+    let mut offset = values_start;
+    for value in values.split(|c| packed && *c == b':') {
+        let mut metric = template.clone();
+        if metric.metric_type == MetricType::Set {
+            if value.is_empty() {
+                return Err(Error::Malformed { field: Field::Value, offset });
+            }
+            metric.member = Some(to_str(value, Field::Value, offset)?);
+        } else {
+            let (sign, number) = match value.first() {
+                Some(&b'-') | Some(&b'+') => (Some(value[0]), &value[1..]),
+                _ => (None, value),
+            };
+            metric.value = parse_number(number).map_err(|e| e.shift(offset))?;
+
+            // Counters cannot be decremented, so only do so if the metric is not a counter.
+            if sign == Some(b'-') && metric.metric_type != MetricType::Counter {
+                metric.value *= -1.0;
+            }
+            metric.explicit_sign = sign.is_some();
         }
+        metrics.push(metric);
+        offset += value.len() + 1;
+    }
+
+    Ok(metrics)
+}
+
+// A name is made of word characters and dots. Almost every name is ASCII, so the characters are
+// only decoded when a byte outside of ASCII shows up.
+fn parse_name(name: &[u8]) -> CapellaResult<&str> {
+    let invalid = Error::InvalidName { offset: 0 };
+    if name.is_empty() {
+        return Err(invalid);
     }
 
-    if let Some(rate) = caps.name("rate") {
-        let r = rate.as_str().parse::<f64>().map_err(Error::from)?;
-        metric.sample_rate = Some(r);
+    let is_name_byte = |c: &u8| c.is_ascii_alphanumeric() || *c == b'_' || *c == b'.';
+    if name.iter().all(is_name_byte) {
+        // Every byte was checked to be ASCII.
+        return Ok(str::from_utf8(name).unwrap());
     }
 
-    Ok(metric)
+    let name = to_str(name, Field::Name, 0)?;
+    if name.chars().all(|c| c.is_alphanumeric() || c == '_' || c == '.') {
+        Ok(name)
+    } else {
+        Err(invalid)
+    }
+}
+
+fn parse_type(metric_type: &[u8]) -> CapellaResult<MetricType> {
+    match metric_type {
+        b"c" => Ok(MetricType::Counter),
+        b"g" => Ok(MetricType::Gauge),
+        b"ms" => Ok(MetricType::Timer),
+        b"s" => Ok(MetricType::Set),
+        b"h" => Ok(MetricType::Histogram),
+        b"d" => Ok(MetricType::Distribution),
+        _ => Err(Error::UnknownType { offset: 0 }),
+    }
+}
+
+// An unsigned number is digits with an optional fractional part, such as `5`, `.5` or `0.5`.
+fn parse_number(number: &[u8]) -> CapellaResult<f64> {
+    let invalid = Error::InvalidValue { field: Field::Value, offset: 0 };
+    let (integer, fraction) = match number.iter().position(|c| *c == b'.') {
+        Some(i) => (&number[..i], &number[i + 1..]),
+        None => (&[][..], number),
+    };
+    if fraction.is_empty() || !integer.iter().chain(fraction).all(u8::is_ascii_digit) {
+        return Err(invalid);
+    }
+
+    // Every byte was checked to be ASCII.
+    str::from_utf8(number).unwrap().parse::<f64>().map_err(|_| invalid)
+}
+
+// A sample rate must be a fraction of the metrics sent, so zero would make no sense. It is always
+// written with a decimal point, such as `0.5` or `1.0`.
+fn parse_rate(rate: &[u8]) -> CapellaResult<f64> {
+    let invalid = Error::InvalidSampleRate { offset: 0 };
+    match rate.iter().position(|c| *c == b'.') {
+        Some(point) if point > 0 => {}
+        _ => return Err(invalid),
+    }
+
+    match parse_number(rate) {
+        Ok(r) if r > 0.0 && r <= 1.0 => Ok(r),
+        _ => Err(invalid),
+    }
+}
+
+// Tags are kept as they were sent and only split once the cache sees them for the first time, but
+// every tag needs a name.
+fn parse_raw_tags(tags: &[u8]) -> CapellaResult<&str> {
+    let tags = to_str(tags, Field::Tags, 0)?;
+    let mut offset = 0;
+    for tag in tags.split(',') {
+        if tag.is_empty() || tag.starts_with(':') {
+            return Err(Error::InvalidTag { offset });
+        }
+        offset += tag.len() + 1;
+    }
+    Ok(tags)
+}
+
+// Check that part of a line is UTF-8, where `offset` is the offset of the part within the line.
+fn to_str(part: &[u8], field: Field, offset: usize) -> CapellaResult<&str> {
+    str::from_utf8(part)
+        .map_err(|e| Error::InvalidUtf8 { field, offset: offset + e.valid_up_to() })
+}
+
+// Return the position of the first occurrence of `needle` in `haystack`.
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|w| w == needle)
 }
 
 #[cfg(test)]
 mod tests {
-    use std::rc::Rc;
-
-    use super::{Metric, MetricType, parse_metric};
+    use super::{Event, Line, Metric, MetricType, ServiceCheck, ServiceCheckStatus, Tag, parse_line,
+                parse_metrics};
+    use error::{Error, Field};
+
+    // Parse a line that holds exactly one metric.
+    fn parse_one(packet: &[u8]) -> Metric<'_> {
+        let mut metrics = parse_metrics(packet).unwrap();
+        assert_eq!(metrics.len(), 1);
+        metrics.remove(0)
+    }
 
     #[test]
     fn bad_parse_cases() {
@@ -129,23 +590,58 @@ mod tests {
                          "",
                          "test|1:",
                          "test:1|c@1",
+                         "test:|s",
+                         "test:1a|g",
                          ":1.0|c",
                          "test|1",
                          "test:1|a",
                          "test:c|c",
-                         "test:1|ms|0.3"];
+                         "test:1|ms|0.3",
+                         "test:1|c|#",
+                         "test:1|c|#a,,b",
+                         "test:1|c|#:value",
+                         "test:1|c|#env:prod|@0.5",
+                         "test:1|c|@0.0",
+                         "test:1|c|@1.5",
+                         "test:1:|ms",
+                         "test:1::2|ms",
+                         "test:1:a|g"];
         for c in &cases {
-            assert!(parse_metric(c.as_bytes()).is_err());
+            assert!(parse_metrics(c.as_bytes()).is_err());
         }
     }
 
+    #[test]
+    fn bad_parse_reasons_and_offsets() {
+        let cases: Vec<(&[u8], Error)> =
+            vec![(b"test", Error::Malformed { field: Field::Value, offset: 4 }),
+                 (b"te st:1|c", Error::InvalidName { offset: 0 }),
+                 (b"te\xffst:1|c", Error::InvalidUtf8 { field: Field::Name, offset: 2 }),
+                 (b"test:1:a|g", Error::InvalidValue { field: Field::Value, offset: 7 }),
+                 (b"test:1|x", Error::UnknownType { offset: 7 }),
+                 (b"test:1|c|@1.5", Error::InvalidSampleRate { offset: 10 }),
+                 (b"test:1|c|#a,,b", Error::InvalidTag { offset: 12 }),
+                 (b"test:1|c|@0.5|#a|x", Error::Malformed { field: Field::Optional, offset: 17 }),
+                 (b"users:\xfe|s", Error::InvalidUtf8 { field: Field::Value, offset: 6 }),
+                 (b"_e{5,4}:title|text|d:soon",
+                  Error::InvalidValue { field: Field::Timestamp, offset: 21 }),
+                 (b"_e{5,9}:title|text", Error::Malformed { field: Field::Text, offset: 14 }),
+                 (b"_sc|db.up|4", Error::InvalidValue { field: Field::Status, offset: 10 }),
+                 (b"_sc|db.up|0|#a,:b", Error::InvalidTag { offset: 15 })];
+        for &(line, ref error) in &cases {
+            assert_eq!(parse_line(line).unwrap_err(), *error,
+                       "parsing {:?}", String::from_utf8_lossy(line));
+        }
+        assert_eq!(Error::UnknownType { offset: 7 }.reason(), "unknown_type");
+    }
+
     #[test]
     fn good_simple_counter() {
         let packet = b"test:1|c";
-        let m1 = parse_metric(packet).unwrap();
+        let m1 = parse_one(packet);
 
         let mut m2 = Metric::new();
-        m2.name = Rc::new(String::from("test"));
+        m2.name = "test";
         m2.value = 1.0;
 
         assert_eq!(m1, m2);
@@ -154,10 +650,10 @@ mod tests {
     #[test]
     fn good_timing_with_rate() {
         let packet = b"test:1|ms|@0.1";
-        let m1 = parse_metric(packet).unwrap();
+        let m1 = parse_one(packet);
 
         let mut m2 = Metric::new();
-        m2.name = Rc::new(String::from("test"));
+        m2.name = "test";
         m2.value = 1.0;
         m2.metric_type = MetricType::Timer;
         m2.sample_rate = Some(0.1);
@@ -168,19 +664,169 @@ mod tests {
     #[test]
     fn good_parse_float_value() {
         let packet = b"test:1.0|g";
-        assert!(parse_metric(packet).is_ok());
+        assert!(parse_metrics(packet).is_ok());
+    }
+
+    #[test]
+    fn good_signed_gauges() {
+        let m1 = parse_one(b"gauge:-1|g");
+        assert_eq!(m1.value, -1.0);
+        assert!(m1.explicit_sign);
+
+        let m2 = parse_one(b"gauge:+2.5|g");
+        assert_eq!(m2.value, 2.5);
+        assert!(m2.explicit_sign);
+
+        let m3 = parse_one(b"gauge:3|g");
+        assert!(!m3.explicit_sign);
+    }
+
+    #[test]
+    fn good_histogram_and_distribution() {
+        let m1 = parse_one(b"payload.bytes:512|h|@0.5");
+        assert_eq!(m1.metric_type, MetricType::Histogram);
+        assert_eq!(m1.value, 512.0);
+        assert_eq!(m1.sample_rate, Some(0.5));
+
+        let m2 = parse_one(b"queue.lag:-1.5|d|#env:prod");
+        assert_eq!(m2.metric_type, MetricType::Distribution);
+        assert_eq!(m2.value, -1.5);
+        assert_eq!(m2.tags().count(), 1);
+    }
+
+    #[test]
+    fn good_string_set_member() {
+        let m1 = parse_one(b"users:user-42@example.com|s");
+
+        let mut m2 = Metric::new();
+        m2.name = "users";
+        m2.member = Some("user-42@example.com");
+        m2.metric_type = MetricType::Set;
+
+        assert_eq!(m1, m2);
+        assert_eq!(parse_one(b"ids:-11|s").member, Some("-11"));
+    }
+
+    #[test]
+    fn good_packed_values() {
+        let metrics = parse_metrics(b"latency:12:-15:9.5|ms|@0.5|#env:prod").unwrap();
+
+        let values: Vec<f64> = metrics.iter().map(|m| m.value).collect();
+        assert_eq!(values, vec![12.0, -15.0, 9.5]);
+        assert!(metrics.iter().all(|m| m.metric_type == MetricType::Timer));
+        assert!(metrics.iter().all(|m| m.sample_rate == Some(0.5) && m.raw_tags == "env:prod"));
+        assert!(!metrics[0].explicit_sign && metrics[1].explicit_sign);
+    }
+
+    #[test]
+    fn set_members_are_not_packed() {
+        let members: Vec<Option<&str>> = parse_metrics(b"users:10.0.0.1:8080|s")
+            .unwrap()
+            .into_iter()
+            .map(|m| m.member)
+            .collect();
+        assert_eq!(members, vec![Some("10.0.0.1:8080")]);
+        assert_eq!(parse_one(b"peers:[::1]:8125|s").member, Some("[::1]:8125"));
+        assert_eq!(parse_one(b"users:a::b|s").member, Some("a::b"));
     }
 
     #[test]
     fn good_nested_metric_name() {
         let packet = b"test.nested.name:1|c";
-        let m1 = parse_metric(packet).unwrap();
+        let m1 = parse_one(packet);
 
         let mut m2 = Metric::new();
-        m2.name = Rc::new(String::from("test.nested.name"));
+        m2.name = "test.nested.name";
         m2.value = 1.0;
         m2.metric_type = MetricType::Counter;
 
         assert_eq!(m1, m2);
     }
+
+    #[test]
+    fn good_tagged_metric() {
+        let packet = b"test:1|c|@0.5|#env:prod,canary";
+        let m1 = parse_one(packet);
+
+        let mut m2 = Metric::new();
+        m2.name = "test";
+        m2.value = 1.0;
+        m2.sample_rate = Some(0.5);
+        m2.raw_tags = "env:prod,canary";
+
+        assert_eq!(m1, m2);
+        assert_eq!(m1.tags().collect::<Vec<Tag>>(),
+                   vec![Tag {
+                            name: String::from("env"),
+                            value: Some(String::from("prod")),
+                        },
+                        Tag {
+                            name: String::from("canary"),
+                            value: None,
+                        }]);
+    }
+
+    #[test]
+    fn good_event() {
+        let line = parse_line(b"_e{6,11}:deploy|web\\nv1.2.3|d:1500000000|p:low|t:info|#env:prod")
+            .unwrap();
+
+        let event = Event {
+            title: String::from("deploy"),
+            text: String::from("web\nv1.2.3"),
+            timestamp: Some(1500000000),
+            priority: Some(String::from("low")),
+            alert_type: Some(String::from("info")),
+            tags: vec![Tag {
+                           name: String::from("env"),
+                           value: Some(String::from("prod")),
+                       }],
+            ..Event::default()
+        };
+        assert_eq!(line, Line::Event(event));
+
+        // The lengths are in bytes, so multibyte titles work as long as they are counted right.
+        match parse_line("_e{5,0}:café|".as_bytes()).unwrap() {
+            Line::Event(e) => assert_eq!(e.title, "café"),
+            other => panic!("parsed {:?}", other),
+        }
+    }
+
+    #[test]
+    fn good_service_check() {
+        let line = parse_line(b"_sc|db.up|2|h:db-1|#env:prod|m:down | since\\nnoon").unwrap();
+
+        let check = ServiceCheck {
+            name: String::from("db.up"),
+            status: ServiceCheckStatus::Critical,
+            timestamp: None,
+            hostname: Some(String::from("db-1")),
+            message: Some(String::from("down | since\nnoon")),
+            tags: vec![Tag {
+                           name: String::from("env"),
+                           value: Some(String::from("prod")),
+                       }],
+        };
+        assert_eq!(line, Line::ServiceCheck(check));
+        assert!(parse_line(b"_sc|db.up|0").is_ok());
+    }
+
+    #[test]
+    fn bad_events_and_service_checks() {
+        let cases: Vec<&[u8]> = vec![b"_e{5,4}:title|tex",
+                                     b"_e{4,4}:title|text",
+                                     b"_e{0,4}:|text",
+                                     b"_e{a,4}:title|text",
+                                     b"_e{5,4}:title|text|x:1",
+                                     b"_e{5,4}:title|text|d:soon",
+                                     "_e{1,0}:é|".as_bytes(),
+                                     b"_sc|db.up",
+                                     b"_sc|db.up|4",
+                                     b"_sc||0",
+                                     b"_sc|db.up|0|z:1",
+                                     b"_sc|db.up|0|#"];
+        for c in &cases {
+            assert!(parse_line(c).is_err(), "parsed {:?}", String::from_utf8_lossy(c));
+        }
+    }
 }
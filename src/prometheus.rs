@@ -0,0 +1,495 @@
+//! The prometheus module is a pull based backend. It serves the metrics from the most recent
+//! flush over HTTP using the Prometheus text exposition format.
+#![deny(missing_docs)]
+
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap};
+use std::io::{self, BufReader, Read};
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::rc::Rc;
+use std::time::Duration;
+
+use futures::{Future, Stream};
+
+use tokio_core::net::TcpListener;
+use tokio_core::reactor::{Handle, Timeout};
+
+use tokio_io::AsyncRead;
+use tokio_io::io::{read_until, write_all};
+
+use backend::{done, Backend, Flush};
+
+use cache::{backend_tag, source_tag, MetricKey};
+
+use parse::Tag;
+
+use snapshot::FlushSnapshot;
+
+const CAPELLA_METRICS_TOTAL: &str = "capella_total_metrics";
+const CAPELLA_BAD_METRICS_TOTAL: &str = "capella_bad_metrics";
+const CAPELLA_BAD_METRICS_BY_REASON: &str = "capella_bad_metrics_by_reason";
+const CAPELLA_BAD_METRICS_BY_SOURCE: &str = "capella_bad_metrics_by_source";
+const CAPELLA_BACKEND_FLUSHES: &str = "capella_backend_flushes";
+const CAPELLA_BACKEND_FLUSH_ERRORS: &str = "capella_backend_flush_errors";
+const CAPELLA_BACKEND_FLUSH_LATENCY: &str = "capella_backend_flush_latency_ms";
+const METRICS_PATH: &str = "/metrics";
+
+// The longest request line we are willing to read, and how long a client has to send it.
+const MAX_REQUEST_LINE: u64 = 1024;
+const REQUEST_TIMEOUT_SECS: u64 = 5;
+
+// The kinds of metric families that capella exposes.
+const COUNTER: &str = "counter";
+const GAUGE: &str = "gauge";
+const SUMMARY: &str = "summary";
+
+// A metric family is a type along with all of the samples that share a name.
+#[derive(Debug)]
+struct Family {
+    kind: &'static str,
+    samples: Vec<String>,
+
+    // Whether a metric of another type was skipped because its name sanitised to this one.
+    clashed: bool,
+}
+
+// How many flushes a counter or timer may be missing from before its running total is dropped.
+const MAX_IDLE_FLUSHES: u64 = 10;
+
+// The running totals needed to keep counters and summaries cumulative between flushes, which is
+// what Prometheus expects when it computes rates. Every total remembers the flush it was last
+// updated in so that totals for metrics which stop arriving can be dropped.
+#[derive(Debug, Default)]
+struct Totals {
+    flushes: u64,
+    counters: HashMap<MetricKey, (f64, u64)>,
+    summaries: HashMap<MetricKey, (f64, f64, u64)>,
+}
+
+impl Totals {
+    // Drop every total that was last updated more than `MAX_IDLE_FLUSHES` flushes ago, before the
+    // current flush is folded in.
+    fn prune(&mut self) {
+        let flushes = self.flushes;
+        self.counters.retain(|_, &mut (_, seen)| flushes - seen <= MAX_IDLE_FLUSHES);
+        self.summaries.retain(|_, &mut (_, _, seen)| flushes - seen <= MAX_IDLE_FLUSHES);
+    }
+}
+
+/// The backend that exposes metrics to a Prometheus server.
+#[derive(Debug)]
+pub struct Prometheus {
+    addr: SocketAddr,
+    exposition: Rc<RefCell<String>>,
+    totals: RefCell<Totals>,
+}
+
+impl Prometheus {
+    /// Construct a new prometheus instance that will listen on the given address.
+    pub fn new<A: ToSocketAddrs>(addr: A) -> io::Result<Prometheus> {
+        Ok(Prometheus {
+            addr: addr.to_socket_addrs()?.next().unwrap(),
+            exposition: Rc::new(RefCell::new(String::new())),
+            totals: RefCell::new(Totals::default()),
+        })
+    }
+
+    // Build the exposition text for the snapshot while folding it into the running totals. Every
+    // running total is exposed until it is pruned, even for a flush the metric was missing from,
+    // so that its series does not disappear between samples.
+    fn render(&self, snapshot: &FlushSnapshot) -> String {
+        let mut families: BTreeMap<String, Family> = BTreeMap::new();
+        let mut totals = self.totals.borrow_mut();
+        totals.flushes += 1;
+        totals.prune();
+        let flushes = totals.flushes;
+
+        for (k, v) in snapshot.counters_iter() {
+            let total = totals.counters.entry(k.clone()).or_insert((0.0, flushes));
+            total.0 += *v;
+            total.1 = flushes;
+        }
+        for (k, &(total, _)) in &totals.counters {
+            add_sample(&mut families, k.name(), COUNTER, "", k.tags(), None, total);
+        }
+
+        for (k, v) in snapshot.gauges_iter() {
+            add_sample(&mut families, k.name(), GAUGE, "", k.tags(), None, *v);
+        }
+
+        for (k, v) in snapshot.sets_iter() {
+            add_sample(&mut families, k.name(), GAUGE, "", k.tags(), None, *v);
+        }
+
+        for (k, stats) in snapshot.timer_data_iter() {
+            let mut count = 0.0;
+            let mut average = 0.0;
+            for (stat, v) in stats {
+                match stat.as_str() {
+                    "count" => count = *v,
+                    "average" => average = *v,
+                    _ => {
+                        if let Some(q) = stat_quantile(stat) {
+                            let quantile = ("quantile", q.as_str());
+                            add_sample(&mut families, k.name(), SUMMARY, "", k.tags(),
+                                       Some(quantile), *v);
+                        }
+                    }
+                }
+            }
+
+            let summary = totals.summaries.entry(k.clone()).or_insert((0.0, 0.0, flushes));
+            summary.0 += average * count;
+            summary.1 += count;
+            summary.2 = flushes;
+        }
+        for (k, &(sum, count, _)) in &totals.summaries {
+            add_sample(&mut families, k.name(), SUMMARY, "_sum", k.tags(), None, sum);
+            add_sample(&mut families, k.name(), SUMMARY, "_count", k.tags(), None, count);
+        }
+
+        add_sample(&mut families, CAPELLA_METRICS_TOTAL, GAUGE, "", &[], None,
+                   snapshot.total_metrics());
+        add_sample(&mut families, CAPELLA_BAD_METRICS_TOTAL, GAUGE, "", &[], None,
+                   snapshot.total_bad_metrics());
+        for (reason, count) in snapshot.bad_metrics_iter() {
+            add_sample(&mut families, CAPELLA_BAD_METRICS_BY_REASON, GAUGE, "", &[],
+                       Some(("reason", reason)), *count as f64);
+        }
+        for (source, count) in snapshot.bad_sources_iter() {
+            add_sample(&mut families, CAPELLA_BAD_METRICS_BY_SOURCE, GAUGE, "",
+                       &[source_tag(source)], None, *count as f64);
+        }
+        for (backend, stats) in snapshot.backend_stats_iter() {
+            let tags = [backend_tag(backend)];
+            add_sample(&mut families, CAPELLA_BACKEND_FLUSHES, GAUGE, "", &tags, None,
+                       stats.flushes as f64);
+            add_sample(&mut families, CAPELLA_BACKEND_FLUSH_ERRORS, GAUGE, "", &tags, None,
+                       stats.errors as f64);
+            add_sample(&mut families, CAPELLA_BACKEND_FLUSH_LATENCY, GAUGE, "", &tags, None,
+                       stats.max_latency.as_secs_f64() * 1000.0);
+        }
+
+        let mut body = String::new();
+        for (name, family) in &families {
+            body.push_str(&format!("# TYPE {} {}\n", name, family.kind));
+            for sample in &family.samples {
+                body.push_str(sample);
+            }
+        }
+
+        body
+    }
+}
+
+impl Backend for Prometheus {
+    fn name(&self) -> &'static str {
+        "prometheus"
+    }
+
+    fn start(&mut self, handle: &Handle) {
+        let listener = TcpListener::bind(&self.addr, handle).unwrap();
+        let exposition = self.exposition.clone();
+        let inner = handle.clone();
+
+        let server = listener.incoming().for_each(move |(sock, _)| {
+            let exposition = exposition.clone();
+            let (reader, writer) = sock.split();
+
+            let timeout = Timeout::new(Duration::from_secs(REQUEST_TIMEOUT_SECS), &inner)?
+                .and_then(|_| -> io::Result<Vec<u8>> {
+                    Err(io::Error::new(io::ErrorKind::TimedOut, "request timed out"))
+                });
+            let request = read_until(BufReader::new(reader.take(MAX_REQUEST_LINE)),
+                                     b'\n',
+                                     Vec::new())
+                .map(|(_, line)| line);
+
+            let response = request.select(timeout)
+                .map(|(line, _)| line)
+                .map_err(|(e, _)| e)
+                .and_then(move |line| write_all(writer, make_response(&line, &exposition.borrow())))
+                .then(|res| {
+                    if let Err(e) = res {
+                        trace!("prometheus request failed: {}", e);
+                    }
+                    Ok(())
+                });
+            inner.spawn(response);
+
+            Ok(())
+        }).map_err(|e| error!("prometheus listener failed: {}", e));
+
+        handle.spawn(server);
+    }
+
+    fn purge_metrics(&self, snapshot: &FlushSnapshot) -> Flush {
+        *self.exposition.borrow_mut() = self.render(snapshot);
+        done()
+    }
+}
+
+// Add a single sample to the family it belongs to, creating the family if needed.
+fn add_sample(families: &mut BTreeMap<String, Family>,
+              name: &str,
+              kind: &'static str,
+              suffix: &str,
+              tags: &[Tag],
+              extra: Option<(&str, &str)>,
+              value: f64) {
+    let name = sanitize_name(name);
+    let sample = format!("{}{}{} {}\n", name, suffix, make_labels(tags, extra),
+                         format_value(value));
+
+    let family = families.entry(name)
+        .or_insert_with(|| Family { kind, samples: Vec::new(), clashed: false });
+    // A family has a single type, so a metric of another type whose name sanitises to the same
+    // one is left out rather than exposed under the wrong type.
+    if family.kind != kind {
+        if !family.clashed {
+            warn!("skipping a {} that is exposed under the same name as a {}: {}",
+                  kind, family.kind, sample.trim_end());
+            family.clashed = true;
+        }
+        return;
+    }
+    family.samples.push(sample);
+}
+
+// Map a timer statistic onto the quantile it represents, if it represents one.
+fn stat_quantile(stat: &str) -> Option<String> {
+    match stat {
+        "min" => Some(String::from("0")),
+        "max" => Some(String::from("1")),
+        "median" => Some(String::from("0.5")),
+        _ => stat.strip_prefix("upper_").and_then(percentile_quantile),
+    }
+}
+
+// Turn a percentile suffix such as `99_9` into its quantile, `0.999`. The decimal point is moved
+// two places rather than dividing by 100, which would not be exact for most percentiles.
+fn percentile_quantile(percentile: &str) -> Option<String> {
+    let mut parts = percentile.splitn(2, '_');
+    let whole = parts.next().unwrap();
+    let fraction = parts.next().unwrap_or("");
+    let is_digits = |s: &str| s.bytes().all(|c| c.is_ascii_digit());
+    if whole.is_empty() || !is_digits(whole) || !is_digits(fraction) {
+        return None;
+    }
+
+    // Pad the whole part so that there are always two digits to move past the point.
+    let whole = format!("{:0>3}", whole);
+    let (whole, moved) = whole.split_at(whole.len() - 2);
+    let whole = match whole.trim_start_matches('0') {
+        "" => "0",
+        whole => whole,
+    };
+    let fraction = format!("{}{}", moved, fraction);
+    let fraction = fraction.trim_end_matches('0');
+
+    if fraction.is_empty() {
+        Some(String::from(whole))
+    } else {
+        Some(format!("{}.{}", whole, fraction))
+    }
+}
+
+// Metric names may only contain ASCII letters, digits, underscores and colons, and they may not
+// start with a digit.
+fn sanitize_name(name: &str) -> String {
+    let mut s: String = name.chars()
+        .map(|c| if c.is_ascii_alphanumeric() || c == '_' || c == ':' { c } else { '_' })
+        .collect();
+    if s.is_empty() || s.starts_with(|c: char| c.is_ascii_digit()) {
+        s.insert(0, '_');
+    }
+
+    s
+}
+
+// Label names follow the same rules as metric names except that colons are not allowed.
+fn sanitize_label(name: &str) -> String {
+    sanitize_name(name).replace(':', "_")
+}
+
+fn escape_label_value(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+// Tags become labels. Tags without a value are given a value of `true`, just like graphite.
+fn make_labels(tags: &[Tag], extra: Option<(&str, &str)>) -> String {
+    let mut labels: Vec<String> = tags.iter()
+        .map(|t| {
+            let value = t.value.as_ref().map_or("true", |v| v.as_str());
+            format!("{}=\"{}\"", sanitize_label(&t.name), escape_label_value(value))
+        })
+        .collect();
+    if let Some((name, value)) = extra {
+        labels.push(format!("{}=\"{}\"", name, value));
+    }
+
+    if labels.is_empty() {
+        return String::new();
+    }
+    format!("{{{}}}", labels.join(","))
+}
+
+fn format_value(value: f64) -> String {
+    if value.is_nan() {
+        String::from("NaN")
+    } else if value.is_infinite() {
+        String::from(if value > 0.0 { "+Inf" } else { "-Inf" })
+    } else {
+        value.to_string()
+    }
+}
+
+// Only `GET /metrics` is served; everything else is a 404.
+fn make_response(request_line: &[u8], body: &str) -> Vec<u8> {
+    let line = String::from_utf8_lossy(request_line);
+    let mut parts = line.split_whitespace();
+    let found = match (parts.next(), parts.next()) {
+        (Some("GET"), Some(path)) => path.split('?').next() == Some(METRICS_PATH),
+        _ => false,
+    };
+
+    let (status, content_type, body) = if found {
+        ("200 OK", "text/plain; version=0.0.4", body)
+    } else {
+        ("404 Not Found", "text/plain", "not found\n")
+    };
+
+    format!("HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            status,
+            content_type,
+            body.len(),
+            body)
+        .into_bytes()
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::{make_response, sanitize_name, stat_quantile, Prometheus, MAX_IDLE_FLUSHES};
+    use cache::CapellaCache;
+    use parse::{Metric, MetricType};
+
+    fn make_metric(name: &str, value: f64, metric_type: MetricType) -> Metric<'_> {
+        let mut m = Metric::new();
+        m.name = name;
+        m.value = value;
+        m.metric_type = metric_type;
+        m
+    }
+
+    #[test]
+    fn names_are_sanitized() {
+        assert_eq!(sanitize_name("api.requests-total"), "api_requests_total");
+        assert_eq!(sanitize_name("5xx"), "_5xx");
+        assert_eq!(sanitize_name("ns:name"), "ns:name");
+    }
+
+    #[test]
+    fn exposition_format() {
+        let prometheus = Prometheus::new("127.0.0.1:9102").unwrap();
+        let mut cache = CapellaCache::default();
+
+        let mut counter = make_metric("api.hits", 2.0, MetricType::Counter);
+        counter.raw_tags = "env:prod";
+        cache.add_metric(&counter);
+        cache.add_metric(&make_metric("temp", 21.5, MetricType::Gauge));
+        cache.add_metric(&make_metric("req", 1.0, MetricType::Timer));
+        cache.add_metric(&make_metric("req", 3.0, MetricType::Timer));
+
+        let body = prometheus.render(&cache.flush(0));
+        assert!(body.contains("# TYPE api_hits counter\napi_hits{env=\"prod\"} 2\n"));
+        assert!(body.contains("# TYPE temp gauge\ntemp 21.5\n"));
+        assert!(body.contains("# TYPE req summary\n"));
+        assert!(body.contains("req{quantile=\"0\"} 1\n"));
+        assert!(body.contains("req{quantile=\"1\"} 3\n"));
+        assert!(body.contains("req_sum 4\nreq_count 2\n"));
+        assert!(body.contains("capella_total_metrics 4\n"));
+
+        // Counters keep accumulating across flushes.
+        cache.add_metric(&counter);
+        let body = prometheus.render(&cache.flush(0));
+        assert!(body.contains("api_hits{env=\"prod\"} 4\n"));
+    }
+
+    #[test]
+    fn idle_totals_are_pruned() {
+        let prometheus = Prometheus::new("127.0.0.1:9102").unwrap();
+        let mut cache = CapellaCache::default();
+
+        cache.add_metric(&make_metric("api.hits", 2.0, MetricType::Counter));
+        cache.add_metric(&make_metric("req", 1.0, MetricType::Timer));
+        prometheus.render(&cache.flush(0));
+
+        for _ in 0..MAX_IDLE_FLUSHES {
+            prometheus.render(&cache.flush(0));
+        }
+        assert_eq!(prometheus.totals.borrow().counters.len(), 1);
+        assert_eq!(prometheus.totals.borrow().summaries.len(), 1);
+
+        prometheus.render(&cache.flush(0));
+        assert!(prometheus.totals.borrow().counters.is_empty());
+        assert!(prometheus.totals.borrow().summaries.is_empty());
+
+        // A counter that comes back starts again from zero.
+        cache.add_metric(&make_metric("api.hits", 2.0, MetricType::Counter));
+        let body = prometheus.render(&cache.flush(0));
+        assert!(body.contains("api_hits 2\n"));
+    }
+
+    #[test]
+    fn idle_totals_are_still_exposed() {
+        let prometheus = Prometheus::new("127.0.0.1:9102").unwrap();
+        let mut cache = CapellaCache::default();
+
+        cache.add_metric(&make_metric("api.hits", 2.0, MetricType::Counter));
+        cache.add_metric(&make_metric("req", 3.0, MetricType::Timer));
+        prometheus.render(&cache.flush(0));
+
+        // Nothing arrives during the next interval, but both series keep their totals.
+        let body = prometheus.render(&cache.flush(0));
+        assert!(body.contains("# TYPE api_hits counter\napi_hits 2\n"));
+        assert!(body.contains("# TYPE req summary\nreq_sum 3\nreq_count 1\n"));
+    }
+
+    #[test]
+    fn clashing_names_keep_one_type() {
+        let prometheus = Prometheus::new("127.0.0.1:9102").unwrap();
+        let mut cache = CapellaCache::default();
+        cache.add_metric(&make_metric("a.b", 1.0, MetricType::Counter));
+        cache.add_metric(&make_metric("a_b", 5.0, MetricType::Gauge));
+        cache.add_metric(&make_metric("a_b", 7.0, MetricType::Timer));
+
+        // Counters are added first, so the gauge and the timer are the ones left out.
+        let body = prometheus.render(&cache.flush(0));
+        assert!(body.contains("# TYPE a_b counter\na_b 1\n# TYPE"));
+        assert_eq!(body.matches("# TYPE a_b ").count(), 1);
+        assert!(!body.contains("a_b 5") && !body.contains("a_b_sum"));
+    }
+
+    #[test]
+    fn percentiles_become_exact_quantiles() {
+        assert_eq!(stat_quantile("upper_99_9").unwrap(), "0.999");
+        assert_eq!(stat_quantile("upper_95").unwrap(), "0.95");
+        assert_eq!(stat_quantile("upper_5").unwrap(), "0.05");
+        assert_eq!(stat_quantile("upper_0_5").unwrap(), "0.005");
+        assert_eq!(stat_quantile("upper_100").unwrap(), "1");
+        assert_eq!(stat_quantile("upper_50").unwrap(), "0.5");
+        assert_eq!(stat_quantile("upper_x"), None);
+        assert_eq!(stat_quantile("mean_95"), None);
+    }
+
+    #[test]
+    fn only_metrics_path_is_served() {
+        let ok = String::from_utf8(make_response(b"GET /metrics HTTP/1.1\r\n", "a 1\n")).unwrap();
+        assert!(ok.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(ok.ends_with("\r\n\r\na 1\n"));
+
+        let missing = String::from_utf8(make_response(b"GET / HTTP/1.1\r\n", "a 1\n")).unwrap();
+        assert!(missing.starts_with("HTTP/1.1 404 Not Found\r\n"));
+    }
+}
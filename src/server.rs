@@ -3,101 +3,735 @@
 
 use std::io;
 use std::env;
-use std::cell::RefCell;
-use std::error::Error;
+use std::fs;
+use std::cell::{Cell, RefCell};
 use std::net::SocketAddr;
+use std::os::unix::fs::{DirBuilderExt, FileTypeExt, PermissionsExt};
+use std::os::unix::net::{self, SocketAddr as UnixSocketAddr};
+use std::path::{Path, PathBuf};
+use std::process;
 use std::rc::Rc;
-use std::time::Duration;
+use std::time::{Duration, Instant};
+
+use bytes::BytesMut;
+
+use chrono::offset::local::Local;
 
 use futures::{Future, Stream};
 
-use tokio_core::net::{UdpCodec, UdpSocket};
-use tokio_core::reactor::Core;
+use tokio_codec::{Decoder, FramedRead};
+
+use tokio_core::net::{TcpListener, UdpCodec, UdpSocket};
+use tokio_core::reactor::{Core, Handle};
 
 use tokio_timer::Timer;
 
+use tokio_uds::{UnixDatagram, UnixDatagramCodec};
+
 use backend::Backend;
 
-use cache::CapellaCache;
+use cache::{CacheConfig, CapellaCache, GaugeRetention, HistogramBins, Source};
+
+use error::{CapellaResult, Error};
+
+use hll::{MAX_PRECISION, MIN_PRECISION};
 
-use parse::{self, Metric};
+use parse;
 
-/// `StatsCodec` defines the UDP parser used to accept packets and returns a new
-/// statistic or an error.
-pub struct StatsCodec;
+// The defaults used to protect the TCP listener when no limits are configured.
+const DEFAULT_TCP_MAX_LINE_LENGTH: usize = 8192;
+const DEFAULT_TCP_MAX_CONNECTIONS: usize = 1024;
+
+/// `StatsCodec` defines the UDP parser used to accept packets. Metrics borrow from the socket's
+/// buffer while they are parsed, so every datagram is added to the cache as it is decoded and
+/// only the sender is passed on.
+pub struct StatsCodec {
+    cache: Rc<RefCell<CapellaCache>>,
+}
+
+impl StatsCodec {
+    /// Create a new codec that adds everything it decodes to the cache.
+    pub fn new(cache: Rc<RefCell<CapellaCache>>) -> StatsCodec {
+        StatsCodec { cache }
+    }
+}
 
 impl UdpCodec for StatsCodec {
-    type In = (SocketAddr, Vec<Metric>);
+    type In = SocketAddr;
     type Out = SocketAddr;
 
     fn decode(&mut self, addr: &SocketAddr, buf: &[u8]) -> io::Result<Self::In> {
-        let mut metrics = Vec::new();
-
-        // See if we got multiple metrics.
-        if buf.contains(&b'\n') {
-            // Based on the behavior of split, we need to filter out zero-length chunks.
-            for m in buf.split(|c| *c == b'\n').filter(|chunk| chunk.len() > 0) {
-                let metric = parse::parse_metric(m);
-                if metric.is_ok() {
-                    metrics.push(metric.unwrap());
+        add_datagram(&mut self.cache.borrow_mut(), Source::Ip(addr.ip()), buf);
+        Ok(*addr)
+    }
+
+    // Since stat collecting is fire and forget, we don't need to write data
+    // back to the client.
+    fn encode(&mut self, addr: Self::Out, _: &mut Vec<u8>) -> SocketAddr {
+        addr
+    }
+}
+
+// Unix datagrams are split and parsed exactly like UDP packets. Clients rarely bind their end of
+// the socket, so every client is counted as the same source.
+impl UnixDatagramCodec for StatsCodec {
+    type In = ();
+    type Out = PathBuf;
+
+    fn decode(&mut self, _: &UnixSocketAddr, buf: &[u8]) -> io::Result<Self::In> {
+        add_datagram(&mut self.cache.borrow_mut(), Source::Unix, buf);
+        Ok(())
+    }
+
+    fn encode(&mut self, path: Self::Out, _: &mut Vec<u8>) -> io::Result<PathBuf> {
+        Ok(path)
+    }
+}
+
+// Split a single datagram into its lines, skipping empty ones.
+fn datagram_lines(buf: &[u8]) -> impl Iterator<Item = &[u8]> {
+    // Based on the behavior of split, we need to filter out zero-length chunks.
+    buf.split(|c| *c == b'\n').filter(|chunk| !chunk.is_empty())
+}
+
+// Parse every line in one datagram and add it to the cache.
+fn add_datagram(cache: &mut CapellaCache, source: Source, buf: &[u8]) {
+    for line in datagram_lines(buf) {
+        add_line(cache, source, line);
+    }
+}
+
+// Parse a single line and add it to the cache. A line that is not valid is counted as bad under
+// its source and the reason it could not be parsed.
+fn add_line(cache: &mut CapellaCache, source: Source, line: &[u8]) {
+    match parse::parse_line(line) {
+        Ok(l) => cache.add_line(&l),
+        Err(e) => bad_line(cache, source, Some(line), &e),
+    }
+}
+
+// Count a bad line, and log it if the source has not used up its share of logged lines for this
+// flush interval.
+fn bad_line(cache: &mut CapellaCache, source: Source, line: Option<&[u8]>, error: &Error) {
+    if cache.bad_line_from(source, error) {
+        match line {
+            Some(line) => {
+                warn!("bad line from {}: {}: {:?}", source, error, String::from_utf8_lossy(line))
+            }
+            None => warn!("bad line from {}: {}", source, error),
+        }
+    } else {
+        trace!("bad line from {}: {}", source, error);
+    }
+}
+
+/// `StatsLineCodec` defines the TCP framing used to split a stream into newline delimited lines,
+/// each of which may hold several packed metrics, an event or a service check. The lines are
+/// parsed by the caller so that metrics can borrow from them. Lines longer than the maximum
+/// length are discarded and reported as a parse error.
+#[derive(Debug)]
+pub struct StatsLineCodec {
+    max_length: usize,
+    discarding: bool,
+}
+
+impl StatsLineCodec {
+    /// Create a new codec that accepts lines up to `max_length` bytes long.
+    pub fn new(max_length: usize) -> StatsLineCodec {
+        StatsLineCodec {
+            max_length,
+            discarding: false,
+        }
+    }
+}
+
+impl Decoder for StatsLineCodec {
+    type Item = CapellaResult<BytesMut>;
+    type Error = io::Error;
+
+    fn decode(&mut self, buf: &mut BytesMut) -> io::Result<Option<Self::Item>> {
+        loop {
+            let newline = match buf.iter().position(|c| *c == b'\n') {
+                Some(i) => i,
+                None => {
+                    // Drop a partial line that is already too long and skip ahead to the next
+                    // newline. It is only reported once.
+                    if buf.len() > self.max_length {
+                        let length = buf.len();
+                        buf.clear();
+                        if !self.discarding {
+                            self.discarding = true;
+                            return Ok(Some(Err(Error::LineTooLong { length })));
+                        }
+                    }
+                    return Ok(None);
                 }
+            };
+
+            let line = buf.split_to(newline + 1);
+            if self.discarding {
+                self.discarding = false;
+                continue;
+            }
+
+            let mut line = line;
+            line.truncate(trimmed_len(&line[..newline]));
+            if line.is_empty() {
+                continue;
             }
-            return Ok((*addr, metrics));
+            if line.len() > self.max_length {
+                return Ok(Some(Err(Error::LineTooLong { length: line.len() })));
+            }
+
+            return Ok(Some(Ok(line)));
+        }
+    }
+
+    fn decode_eof(&mut self, buf: &mut BytesMut) -> io::Result<Option<Self::Item>> {
+        if let Some(line) = self.decode(buf)? {
+            return Ok(Some(line));
         }
 
-        // We only got one metric sent.
-        let metric = parse::parse_metric(buf);
-        if metric.is_ok() {
-            metrics.push(metric.unwrap());
+        // The final line of a stream does not need a trailing newline.
+        let mut line = buf.split_off(0);
+        let len = trimmed_len(&line);
+        line.truncate(len);
+        if line.is_empty() || self.discarding {
+            return Ok(None);
+        }
+        if line.len() > self.max_length {
+            return Ok(Some(Err(Error::LineTooLong { length: line.len() })));
         }
 
-        Ok((*addr, metrics))
+        Ok(Some(Ok(line)))
     }
+}
 
-    // Since stat collecting is fire and forget, we don't need to write data
-    // back to the client.
-    fn encode(&mut self, addr: Self::Out, _: &mut Vec<u8>) -> SocketAddr {
-        addr
+// The length of a line without the carriage return of a line that ended with CRLF.
+fn trimmed_len(line: &[u8]) -> usize {
+    match line.last() {
+        Some(&b'\r') => line.len() - 1,
+        _ => line.len(),
     }
 }
 
-/// This starts up the UDP server with the default backend being a graphite host.
-/// Other backends can be specified by modifying the main program.
-pub fn start_udp_server<B: Backend>(backend: B) {
-    let cache = Rc::new(RefCell::new(CapellaCache::default()));
+// Accept StatsD lines over TCP. Every connection feeds the same cache as the UDP socket, and
+// connections over the limit are closed as soon as they are accepted.
+fn spawn_tcp_listener(addr: &SocketAddr, handle: &Handle, cache: Rc<RefCell<CapellaCache>>) {
+    let max_line_length = env::var("CAPELLA_TCP_MAX_LINE_LENGTH")
+        .map(|l| l.parse::<usize>().unwrap())
+        .unwrap_or(DEFAULT_TCP_MAX_LINE_LENGTH);
+    let max_connections = env::var("CAPELLA_TCP_MAX_CONNECTIONS")
+        .map(|c| c.parse::<usize>().unwrap())
+        .unwrap_or(DEFAULT_TCP_MAX_CONNECTIONS);
+
+    let listener = TcpListener::bind(addr, handle).unwrap();
+    let connections = Rc::new(Cell::new(0));
+    let inner = handle.clone();
+
+    let server = listener.incoming().for_each(move |(sock, peer)| {
+        if connections.get() >= max_connections {
+            warn!("rejecting TCP connection from {}: too many connections", peer);
+            return Ok(());
+        }
+        connections.set(connections.get() + 1);
+
+        let cache = cache.clone();
+        let connections = connections.clone();
+        let source = Source::Ip(peer.ip());
+        let lines = FramedRead::new(sock, StatsLineCodec::new(max_line_length))
+            .for_each(move |line| {
+                let mut cache = cache.borrow_mut();
+                match line {
+                    Ok(l) => add_line(&mut cache, source, &l),
+                    // A line that was too long has already been thrown away.
+                    Err(e) => bad_line(&mut cache, source, None, &e),
+                }
+                Ok(())
+            })
+            .then(move |res| {
+                if let Err(e) = res {
+                    trace!("TCP connection from {} failed: {}", peer, e);
+                }
+                connections.set(connections.get() - 1);
+                Ok(())
+            });
+        inner.spawn(lines);
+
+        Ok(())
+    }).map_err(|e| error!("TCP listener failed: {}", e));
+
+    handle.spawn(server);
+}
+
+// Remove a socket file left behind by a previous run. A socket that still has a live reader, or a
+// path that is not a socket at all, is left alone and binding will fail.
+fn remove_stale_socket(path: &Path) -> io::Result<()> {
+    let metadata = match fs::symlink_metadata(path) {
+        Ok(metadata) => metadata,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e),
+    };
+
+    if !metadata.file_type().is_socket() {
+        return Err(io::Error::new(io::ErrorKind::AlreadyExists,
+                                  "refusing to replace a file that is not a socket"));
+    }
+    if net::UnixDatagram::unbound()?.connect(path).is_ok() {
+        return Err(io::Error::new(io::ErrorKind::AddrInUse, "the socket is still in use"));
+    }
+
+    info!("removing stale socket {}", path.display());
+    fs::remove_file(path)
+}
+
+// Parse the permissions of the Unix socket, which are given in octal like `chmod` takes them.
+fn parse_socket_mode(mode: &str) -> io::Result<u32> {
+    u32::from_str_radix(mode, 8)
+        .ok()
+        .filter(|mode| *mode <= 0o777)
+        .ok_or_else(|| {
+            let reason = format!("{:?} is not an octal mode such as 660", mode);
+            io::Error::new(io::ErrorKind::InvalidInput, reason)
+        })
+}
+
+// Bind a Unix socket that already has the given permissions once it can be reached. A socket can
+// only be given permissions after it is bound, so it is bound in a directory that only capella can
+// reach and then moved into place.
+fn bind_unix_socket(path: &Path, mode: Option<u32>, handle: &Handle) -> io::Result<UnixDatagram> {
+    let mode = match mode {
+        Some(mode) => mode,
+        None => return UnixDatagram::bind(path, handle),
+    };
+    let name = path.file_name().ok_or_else(|| {
+        io::Error::new(io::ErrorKind::InvalidInput, "the socket path has no file name")
+    })?;
+
+    let private = path.with_file_name(format!(".{}.{}", name.to_string_lossy(), process::id()));
+    fs::DirBuilder::new().mode(0o700).create(&private)?;
+    let staged = private.join(name);
+    let socket = UnixDatagram::bind(&staged, handle).and_then(|socket| {
+        fs::set_permissions(&staged, fs::Permissions::from_mode(mode))?;
+        fs::rename(&staged, path)?;
+        Ok(socket)
+    });
+
+    if socket.is_err() {
+        let _ = fs::remove_file(&staged);
+    }
+    let _ = fs::remove_dir(&private);
+    socket
+}
+
+// Accept StatsD datagrams on a Unix socket, with the given permissions if any. They feed the same
+// cache as the UDP socket.
+fn spawn_unix_listener(path: &Path,
+                       mode: Option<u32>,
+                       handle: &Handle,
+                       cache: Rc<RefCell<CapellaCache>>)
+                       -> io::Result<()> {
+    remove_stale_socket(path)?;
+    let socket = bind_unix_socket(path, mode, handle)?;
+
+    let (_, stream) = socket.framed(StatsCodec::new(cache)).split();
+    let datagrams = stream.for_each(|()| Ok(()))
+        .map_err(|e| error!("unix socket failed: {}", e));
+
+    handle.spawn(datagrams);
+    Ok(())
+}
+
+// Build the cache configuration from the environment, keeping the defaults for anything unset.
+fn make_cache_config(flush_duration: u64) -> CacheConfig {
+    let mut config = CacheConfig {
+        flush_interval: flush_duration as f64,
+        ..CacheConfig::default()
+    };
+
+    if let Ok(percentiles) = env::var("CAPELLA_PERCENTILES") {
+        config.percentiles = percentiles.split(',')
+            .map(|p| {
+                let p = p.trim().parse::<f64>().unwrap();
+                assert!(p > 0.0 && p <= 100.0, "percentiles must be in (0, 100]: {}", p);
+                p
+            })
+            .collect();
+    }
+
+    if let Ok(retention) = env::var("CAPELLA_GAUGE_RETENTION") {
+        config.gauge_retention = match retention.as_str() {
+            "forever" => GaugeRetention::Forever,
+            "clear" => GaugeRetention::Clear,
+            flushes => GaugeRetention::Idle(flushes.parse::<u32>().unwrap()),
+        };
+    }
+
+    // Deleting idle stats can be set for every type at once and then overridden per type.
+    let delete_idle = env::var("CAPELLA_DELETE_IDLE_STATS")
+        .map(|d| d.parse::<bool>().unwrap())
+        .unwrap_or(true);
+    let delete_type = |var: &str| {
+        env::var(var).map(|d| d.parse::<bool>().unwrap()).unwrap_or(delete_idle)
+    };
+    config.delete_idle_counters = delete_type("CAPELLA_DELETE_COUNTERS");
+    config.delete_idle_timers = delete_type("CAPELLA_DELETE_TIMERS");
+    config.delete_idle_sets = delete_type("CAPELLA_DELETE_SETS");
+
+    if let Ok(prefixes) = env::var("CAPELLA_HLL_PREFIXES") {
+        config.hll_prefixes = prefixes.split(',').map(|p| String::from(p.trim())).collect();
+    }
+    if let Ok(precision) = env::var("CAPELLA_HLL_PRECISION") {
+        let precision = precision.parse::<u8>().unwrap();
+        assert!((MIN_PRECISION..=MAX_PRECISION).contains(&precision),
+                "the HyperLogLog precision must be between {} and {}",
+                MIN_PRECISION,
+                MAX_PRECISION);
+        config.hll_precision = precision;
+    }
+
+    // Each histogram is a pattern and its bins, such as `api.=10,100,inf`, separated by `;`.
+    if let Ok(histograms) = env::var("CAPELLA_HISTOGRAMS") {
+        config.histograms = histograms.split(';')
+            .map(|h| {
+                let mut parts = h.splitn(2, '=');
+                let pattern = String::from(parts.next().unwrap().trim());
+                let mut bins: Vec<f64> = parts.next()
+                    .expect("a histogram needs a list of bins")
+                    .split(',')
+                    .map(|b| b.trim().parse::<f64>().unwrap())
+                    .collect();
+                bins.sort_by(|a, b| a.partial_cmp(b).unwrap());
+                HistogramBins { pattern, bins }
+            })
+            .collect();
+    }
+
+    if let Ok(prefixes) = env::var("CAPELLA_TIMER_SKETCH_PREFIXES") {
+        config.timer_sketch_prefixes =
+            prefixes.split(',').map(|p| String::from(p.trim())).collect();
+    }
+    if let Ok(accuracy) = env::var("CAPELLA_SKETCH_ACCURACY") {
+        let accuracy = accuracy.parse::<f64>().unwrap();
+        assert!(accuracy > 0.0 && accuracy < 1.0, "the sketch accuracy must be in (0, 1)");
+        config.sketch_accuracy = accuracy;
+    }
+    if let Ok(limit) = env::var("CAPELLA_BAD_LINE_LOG_LIMIT") {
+        config.bad_line_log_limit = limit.parse::<u64>().unwrap();
+    }
+
+    config
+}
+
+// Take a snapshot of the cache and hand it to every backend. Each backend's flush runs on its
+// own, so a backend that fails or is slow does not hold up the others. How long each one took and
+// whether it failed is recorded in the cache once it finishes.
+fn flush(cache: &Rc<RefCell<CapellaCache>>, backends: &[Box<dyn Backend>], handle: &Handle) {
+    let snapshot = cache.borrow_mut().flush(Local::now().timestamp());
+    let flushes: Vec<_> = backends.iter()
+        .map(|backend| {
+            let started = Instant::now();
+            let events = backend.purge_events(&snapshot);
+            let metrics = backend.purge_metrics(&snapshot);
+            (backend.name(), started, events.join(metrics))
+        })
+        .collect();
+
+    for (name, started, flush) in flushes {
+        let cache = cache.clone();
+        handle.spawn(flush.then(move |res| {
+            if let Err(ref e) = res {
+                error!("the {} backend failed to flush: {}", name, e);
+            }
+            cache.borrow_mut().backend_flush_finished(name, started.elapsed(), res.is_err());
+            Ok(())
+        }));
+    }
+}
+
+/// This starts up the UDP server and flushes the metrics it receives to every backend given.
+pub fn start_udp_server(mut backends: Vec<Box<dyn Backend>>) {
+    let flush_duration = env::var("CAPELLA_FLUSH_DURATION").unwrap().parse::<u64>().unwrap();
+    let cache = Rc::new(RefCell::new(CapellaCache::new(make_cache_config(flush_duration))));
     let mut core = Core::new().unwrap();
     let handle = core.handle();
+    for backend in &mut backends {
+        backend.start(&handle);
+    }
+
     let capella_addr = env::var("CAPELLA_LISTENER").unwrap();
     let addr: SocketAddr = capella_addr.parse().unwrap();
     let s = UdpSocket::bind(&addr, &handle).unwrap();
 
-    let (_, stream) = s.framed(StatsCodec).split();
+    let (_, stream) = s.framed(StatsCodec::new(cache.clone())).split();
+
+    // The TCP listener is optional and shares the cache with the UDP socket.
+    if let Ok(tcp_addr) = env::var("CAPELLA_TCP_LISTENER") {
+        let tcp_addr: SocketAddr = tcp_addr.parse().unwrap();
+        spawn_tcp_listener(&tcp_addr, &handle, cache.clone());
+    }
+
+    // The Unix socket is optional as well.
+    if let Ok(unix_path) = env::var("CAPELLA_UNIX_SOCKET") {
+        let mode = env::var("CAPELLA_UNIX_SOCKET_MODE").ok().map(|mode| {
+            parse_socket_mode(&mode)
+                .unwrap_or_else(|e| panic!("invalid CAPELLA_UNIX_SOCKET_MODE: {}", e))
+        });
+        spawn_unix_listener(Path::new(&unix_path), mode, &handle, cache.clone())
+            .unwrap_or_else(|e| panic!("failed to listen on the unix socket {}: {}", unix_path, e));
+    }
 
     // This sets up the purge timer utilizing the event loop.
-    let flush_duration = env::var("CAPELLA_FLUSH_DURATION").unwrap().parse::<u64>().unwrap();
     let timer = Timer::default().interval(Duration::new(flush_duration, 0));
     let future_t = timer.for_each(|()| {
-        backend.purge_metrics(&mut cache.borrow_mut());
         trace!("flushing metrics");
+        flush(&cache, &backends, &handle);
         Ok(())
     }).map_err(|e| {
-        io::Error::new(io::ErrorKind::Other, e.description())
+        io::Error::other(e.to_string())
     });
 
-    // This is the event loop stream in which all values are parsed.
-    let events = stream.for_each(|(_, metrics)| {
-        if metrics.is_empty() {
-            trace!("no valid metrics were sent");
-            cache.borrow_mut().bad_metric_count_increase();
+    // This is the event loop stream in which all values are parsed. The codec adds them to the
+    // cache as they are decoded.
+    let events = stream.for_each(|_| Ok(()));
+    let f = events.join(future_t);
+
+    drop(core.run(f));
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::{Cell, RefCell};
+    use std::env;
+    use std::fs;
+    use std::io::{self, ErrorKind};
+    use std::os::unix::fs::PermissionsExt;
+    use std::os::unix::net::UnixDatagram;
+    use std::path::PathBuf;
+    use std::process;
+    use std::rc::Rc;
+    use std::time::Duration;
+
+    use bytes::BytesMut;
+
+    use futures::future;
+
+    use tokio_codec::Decoder;
+
+    use tokio_core::reactor::Core;
+
+    use backend::{Backend, Flush};
+    use cache::{CapellaCache, Source};
+    use error::{CapellaResult, Error};
+    use parse::{parse_line, Line, Metric};
+    use snapshot::FlushSnapshot;
+
+    use super::{add_datagram, datagram_lines, flush, parse_socket_mode, remove_stale_socket,
+                spawn_unix_listener, StatsLineCodec};
+
+    // A backend that records how many counters it was handed and may fail every flush.
+    struct TestBackend {
+        name: &'static str,
+        fail: bool,
+        counters: Rc<Cell<usize>>,
+    }
+
+    impl Backend for TestBackend {
+        fn name(&self) -> &'static str {
+            self.name
         }
 
-        for m in &metrics {
-            cache.borrow_mut().add_metric(m);
+        fn purge_metrics(&self, snapshot: &FlushSnapshot) -> Flush {
+            self.counters.set(self.counters.get() + snapshot.counters_iter().count());
+            if self.fail {
+                return Box::new(future::err(io::Error::other("unreachable")));
+            }
+            Box::new(future::ok(()))
         }
+    }
 
-        Ok(())
-    });
-    let f = events.join(future_t);
+    // Parse every line of a datagram.
+    fn parse_datagram(buf: &[u8]) -> Vec<CapellaResult<Line<'_>>> {
+        datagram_lines(buf).map(parse_line).collect()
+    }
 
-    drop(core.run(f));
+    // Flatten the metrics out of the parsed lines, skipping bad lines, events and service checks.
+    fn metrics(lines: Vec<CapellaResult<Line>>) -> Vec<Metric> {
+        lines.into_iter()
+            .flat_map(|l| match l {
+                Ok(Line::Metrics(metrics)) => metrics,
+                _ => Vec::new(),
+            })
+            .collect()
+    }
+
+    // Parse a framed line and return the value of its first metric.
+    fn first_value(line: BytesMut) -> f64 {
+        metrics(vec![parse_line(&line)])[0].value
+    }
+
+    #[test]
+    fn datagram_with_several_lines() {
+        let metrics = metrics(parse_datagram(b"a:1|c\nbad\n\nb:2|g\n"));
+
+        assert_eq!(metrics.len(), 2);
+        assert_eq!(metrics[0].name, "a");
+        assert_eq!(metrics[1].name, "b");
+    }
+
+    #[test]
+    fn datagram_with_packed_lines() {
+        let lines = parse_datagram(b"a:1:2:3|ms\nb:4|c\n_sc|up|0\nc:5:x|g\nd:6:7|g");
+        assert_eq!(lines.len(), 5);
+        assert!(lines[3].is_err());
+        let metrics = metrics(lines);
+
+        let values: Vec<f64> = metrics.iter().map(|m| m.value).collect();
+        assert_eq!(values, vec![1.0, 2.0, 3.0, 4.0, 6.0, 7.0]);
+        assert_eq!(metrics[2].name, "a");
+        assert_eq!(metrics[5].name, "d");
+    }
+
+    #[test]
+    fn bad_lines_counted_by_reason() {
+        let mut cache = CapellaCache::default();
+        add_datagram(&mut cache, Source::Unix, b"a:1|c\nb:1|x\nc:1|@2\nd:1|y\n\xff:1|c");
+
+        let reasons: Vec<(&str, u64)> = cache.bad_metrics_iter().map(|(r, c)| (*r, *c)).collect();
+        assert_eq!(reasons, vec![("invalid_utf8", 1), ("unknown_type", 3)]);
+        assert_eq!(cache.total_bad_metrics(), 4.0);
+        assert_eq!(cache.total_metrics(), 5.0);
+        assert_eq!(cache.bad_sources_iter().collect::<Vec<_>>(), vec![(&Source::Unix, &4)]);
+    }
+
+    #[test]
+    fn flush_isolates_backends() {
+        let mut core = Core::new().unwrap();
+        let cache = Rc::new(RefCell::new(CapellaCache::default()));
+        let counters = Rc::new(Cell::new(0));
+        let backends: Vec<Box<dyn Backend>> = vec![
+            Box::new(TestBackend { name: "broken", fail: true, counters: counters.clone() }),
+            Box::new(TestBackend { name: "working", fail: false, counters: counters.clone() }),
+        ];
+
+        add_datagram(&mut cache.borrow_mut(), Source::Unix, b"a:1|c\nb:1|c");
+        flush(&cache, &backends, &core.handle());
+        core.turn(Some(Duration::from_millis(0)));
+
+        // Both backends were handed the same counters, and the failure of one did not stop the
+        // other from finishing.
+        assert_eq!(counters.get(), 4);
+        let cache = cache.borrow();
+        assert_eq!(cache.counters_iter().count(), 0);
+        let stats: Vec<(&str, u64, u64)> = cache.backend_stats_iter()
+            .map(|(name, stats)| (*name, stats.flushes, stats.errors))
+            .collect();
+        assert_eq!(stats, vec![("broken", 1, 1), ("working", 1, 0)]);
+    }
+
+    #[test]
+    fn line_codec_splits_lines() {
+        let mut codec = StatsLineCodec::new(64);
+        let mut buf = BytesMut::from(&b"a:1|c\r\n\nb:2|g\nc:3|c"[..]);
+
+        assert_eq!(first_value(codec.decode(&mut buf).unwrap().unwrap().unwrap()), 1.0);
+        assert_eq!(first_value(codec.decode(&mut buf).unwrap().unwrap().unwrap()), 2.0);
+        assert!(codec.decode(&mut buf).unwrap().is_none());
+        assert_eq!(first_value(codec.decode_eof(&mut buf).unwrap().unwrap().unwrap()), 3.0);
+        assert!(codec.decode_eof(&mut buf).unwrap().is_none());
+    }
+
+    #[test]
+    fn line_codec_discards_long_lines() {
+        let mut codec = StatsLineCodec::new(8);
+        let mut buf = BytesMut::from(&b"a.very.long"[..]);
+
+        // The partial line is reported once and dropped.
+        assert_eq!(codec.decode(&mut buf).unwrap().unwrap().unwrap_err(),
+                   Error::LineTooLong { length: 11 });
+        buf.extend_from_slice(b".name:1|c");
+        assert!(codec.decode(&mut buf).unwrap().is_none());
+
+        // The rest of the long line is skipped and the next line is parsed.
+        buf.extend_from_slice(b"\na:1|c\n");
+        assert_eq!(first_value(codec.decode(&mut buf).unwrap().unwrap().unwrap()), 1.0);
+        assert!(codec.decode(&mut buf).unwrap().is_none());
+    }
+
+    fn socket_path(name: &str) -> PathBuf {
+        let path = env::temp_dir().join(format!("capella-{}-{}.sock", name, process::id()));
+        let _ = fs::remove_file(&path);
+        path
+    }
+
+    #[test]
+    fn missing_socket_is_ignored() {
+        assert!(remove_stale_socket(&socket_path("missing")).is_ok());
+    }
+
+    #[test]
+    fn stale_socket_is_removed() {
+        let path = socket_path("stale");
+        drop(UnixDatagram::bind(&path).unwrap());
+
+        remove_stale_socket(&path).unwrap();
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn live_socket_is_kept() {
+        let path = socket_path("live");
+        let _live = UnixDatagram::bind(&path).unwrap();
+
+        assert_eq!(remove_stale_socket(&path).unwrap_err().kind(), ErrorKind::AddrInUse);
+        assert!(path.exists());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn regular_file_is_kept() {
+        let path = socket_path("file");
+        fs::write(&path, b"not a socket").unwrap();
+
+        assert_eq!(remove_stale_socket(&path).unwrap_err().kind(), ErrorKind::AlreadyExists);
+        assert_eq!(fs::read(&path).unwrap(), b"not a socket");
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn unix_listener_sets_mode() {
+        let path = socket_path("mode");
+        drop(UnixDatagram::bind(&path).unwrap());
+
+        let mut core = Core::new().unwrap();
+        let cache = Rc::new(RefCell::new(CapellaCache::default()));
+        spawn_unix_listener(&path, Some(0o640), &core.handle(), cache.clone()).unwrap();
+        assert_eq!(fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o640);
+
+        // The socket was bound in a private directory that is gone once it was moved into place.
+        let staged = format!(".{}.", path.file_name().unwrap().to_string_lossy());
+        assert!(!fs::read_dir(env::temp_dir())
+            .unwrap()
+            .any(|e| e.unwrap().file_name().to_string_lossy().starts_with(&staged)));
+
+        // The stale socket was replaced and the new one feeds the cache.
+        UnixDatagram::unbound().unwrap().send_to(b"a:1|c", &path).unwrap();
+        for _ in 0..10 {
+            if cache.borrow().counters_iter().count() == 1 {
+                break;
+            }
+            core.turn(Some(Duration::from_millis(50)));
+        }
+        assert_eq!(cache.borrow().counters_iter().count(), 1);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn socket_modes_are_octal() {
+        assert_eq!(parse_socket_mode("660").unwrap(), 0o660);
+        assert_eq!(parse_socket_mode("0600").unwrap(), 0o600);
+        for bad in &["", "rw", "680", "1777"] {
+            assert_eq!(parse_socket_mode(bad).unwrap_err().kind(), ErrorKind::InvalidInput);
+        }
+    }
 }
@@ -0,0 +1,369 @@
+//! The sketch module implements a DDSketch, a quantile sketch with a relative error guarantee
+//! that can be merged with other sketches of the same accuracy.
+#![deny(missing_docs)]
+
+use std::collections::{btree_map, BTreeMap};
+
+/// The relative accuracy used when none is configured.
+pub const DEFAULT_RELATIVE_ACCURACY: f64 = 0.01;
+
+/// The number of bins kept for each sign before the lowest bins are collapsed.
+pub const DEFAULT_MAX_BINS: usize = 2048;
+
+// Values closer to zero than this are counted in the zero bin.
+const MIN_INDEXABLE: f64 = 1e-9;
+
+/// A `DDSketch` summarises a stream of values in logarithmically sized bins. Any quantile it
+/// returns is within the relative accuracy of the true value, as long as the bins holding that
+/// quantile have not been collapsed. The count, sum, minimum, maximum and standard deviation are
+/// kept exactly.
+#[derive(Clone, Debug)]
+pub struct DDSketch {
+    relative_accuracy: f64,
+    gamma: f64,
+    ln_gamma: f64,
+    max_bins: usize,
+    positive: BTreeMap<i32, f64>,
+    negative: BTreeMap<i32, f64>,
+    zero: f64,
+    count: f64,
+    sum: f64,
+    sum_of_squares: f64,
+    min: f64,
+    max: f64,
+}
+
+impl DDSketch {
+    /// Create a new, empty sketch. The relative accuracy must be between zero and one, and at
+    /// most `max_bins` bins are kept for positive and for negative values.
+    pub fn new(relative_accuracy: f64, max_bins: usize) -> DDSketch {
+        let gamma = (1.0 + relative_accuracy) / (1.0 - relative_accuracy);
+        DDSketch {
+            relative_accuracy,
+            gamma,
+            ln_gamma: gamma.ln(),
+            max_bins: max_bins.max(1),
+            positive: BTreeMap::new(),
+            negative: BTreeMap::new(),
+            zero: 0.0,
+            count: 0.0,
+            sum: 0.0,
+            sum_of_squares: 0.0,
+            min: f64::INFINITY,
+            max: f64::NEG_INFINITY,
+        }
+    }
+
+    /// Return the relative accuracy the sketch was created with.
+    pub fn relative_accuracy(&self) -> f64 {
+        self.relative_accuracy
+    }
+
+    /// Add a single value to the sketch.
+    pub fn insert(&mut self, value: f64) {
+        self.insert_weighted(value, 1.0);
+    }
+
+    /// Add a value that stands in for `weight` values, such as a sampled metric.
+    pub fn insert_weighted(&mut self, value: f64, weight: f64) {
+        if value.abs() < MIN_INDEXABLE {
+            self.zero += weight;
+        } else if value > 0.0 {
+            let index = self.index(value);
+            add_to_bins(&mut self.positive, index, weight, self.max_bins);
+        } else {
+            let index = self.index(-value);
+            add_to_bins(&mut self.negative, index, weight, self.max_bins);
+        }
+
+        self.count += weight;
+        self.sum += value * weight;
+        self.sum_of_squares += value * value * weight;
+        self.min = self.min.min(value);
+        self.max = self.max.max(value);
+    }
+
+    /// Fold another sketch into this one, as if every value it holds had been inserted here.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the sketches were created with a different relative accuracy.
+    pub fn merge(&mut self, other: &DDSketch) {
+        assert!(self.gamma == other.gamma,
+                "only sketches with the same relative accuracy can be merged");
+
+        for (index, count) in &other.positive {
+            add_to_bins(&mut self.positive, *index, *count, self.max_bins);
+        }
+        for (index, count) in &other.negative {
+            add_to_bins(&mut self.negative, *index, *count, self.max_bins);
+        }
+
+        self.zero += other.zero;
+        self.count += other.count;
+        self.sum += other.sum;
+        self.sum_of_squares += other.sum_of_squares;
+        self.min = self.min.min(other.min);
+        self.max = self.max.max(other.max);
+    }
+
+    /// Return the value at the given quantile, between 0 and 1, or `None` if the sketch is empty.
+    pub fn quantile(&self, q: f64) -> Option<f64> {
+        if self.is_empty() {
+            return None;
+        }
+
+        let rank = q.clamp(0.0, 1.0) * (self.count - 1.0);
+        let mut seen = 0.0;
+
+        // Walk from the most negative value up to the largest positive one.
+        for (index, count) in self.negative.iter().rev() {
+            seen += *count;
+            if seen > rank {
+                return Some(self.clamp(-self.value(*index)));
+            }
+        }
+
+        seen += self.zero;
+        if seen > rank {
+            return Some(self.clamp(0.0));
+        }
+
+        for (index, count) in &self.positive {
+            seen += *count;
+            if seen > rank {
+                return Some(self.clamp(self.value(*index)));
+            }
+        }
+
+        Some(self.max)
+    }
+
+    /// Return the approximate number of values that are at most `bound`, accounting for their
+    /// weights. A value is counted by the bin that holds it, so values within the relative
+    /// accuracy of the bound may land on either side of it.
+    pub fn count_at_most(&self, bound: f64) -> f64 {
+        if bound >= self.max {
+            return self.count;
+        }
+
+        let negative: f64 = self.negative
+            .iter()
+            .filter(|&(index, _)| -self.value(*index) <= bound)
+            .map(|(_, count)| *count)
+            .sum();
+        let zero = if bound >= 0.0 { self.zero } else { 0.0 };
+        let positive: f64 = self.positive
+            .iter()
+            .take_while(|&(index, _)| self.value(*index) <= bound)
+            .map(|(_, count)| *count)
+            .sum();
+
+        negative + zero + positive
+    }
+
+    /// Return the population standard deviation of the values added, if any.
+    pub fn std_dev(&self) -> Option<f64> {
+        if self.is_empty() {
+            return None;
+        }
+
+        let mean = self.sum / self.count;
+        // Rounding can leave the variance a hair below zero when every value is the same.
+        Some((self.sum_of_squares / self.count - mean * mean).max(0.0).sqrt())
+    }
+
+    /// Return the number of values added, accounting for their weights.
+    pub fn count(&self) -> f64 {
+        self.count
+    }
+
+    /// Return the sum of the values added, accounting for their weights.
+    pub fn sum(&self) -> f64 {
+        self.sum
+    }
+
+    /// Return the smallest value added, if any.
+    pub fn min(&self) -> Option<f64> {
+        if self.is_empty() { None } else { Some(self.min) }
+    }
+
+    /// Return the largest value added, if any.
+    pub fn max(&self) -> Option<f64> {
+        if self.is_empty() { None } else { Some(self.max) }
+    }
+
+    /// Return the number of values too close to zero to be given a bin, accounting for their
+    /// weights.
+    pub fn zero_count(&self) -> f64 {
+        self.zero
+    }
+
+    /// Return an iterator over the bins that hold positive values, ordered by index. The bin with
+    /// index `i` counts the values in `(gamma^(i-1), gamma^i]`, where `gamma` is
+    /// `(1 + relative_accuracy) / (1 - relative_accuracy)`, so sketches with the same accuracy can
+    /// be merged by adding up the counts of bins with the same index.
+    pub fn positive_bins(&self) -> btree_map::Iter<'_, i32, f64> {
+        self.positive.iter()
+    }
+
+    /// Return an iterator over the bins that hold negative values, ordered by index. Negative
+    /// values are binned by their magnitude, the same way as `positive_bins`.
+    pub fn negative_bins(&self) -> btree_map::Iter<'_, i32, f64> {
+        self.negative.iter()
+    }
+
+    /// Return whether nothing has been added to the sketch.
+    pub fn is_empty(&self) -> bool {
+        self.count == 0.0
+    }
+
+    /// Remove every value from the sketch.
+    pub fn clear(&mut self) {
+        *self = DDSketch::new(self.relative_accuracy, self.max_bins);
+    }
+
+    // The bin that holds a positive value.
+    fn index(&self, value: f64) -> i32 {
+        (value.ln() / self.ln_gamma).ceil() as i32
+    }
+
+    // The value that represents a bin, which is within the relative accuracy of every value in it.
+    fn value(&self, index: i32) -> f64 {
+        2.0 * self.gamma.powi(index) / (self.gamma + 1.0)
+    }
+
+    // Bins only approximate their values, so keep answers within what was actually seen.
+    fn clamp(&self, value: f64) -> f64 {
+        value.max(self.min).min(self.max)
+    }
+}
+
+// Add a count to a bin. Once there are too many bins, the lowest ones are folded into the next
+// bin up, which keeps the memory bounded and the accuracy of the higher quantiles.
+fn add_to_bins(bins: &mut BTreeMap<i32, f64>, index: i32, count: f64, max_bins: usize) {
+    *bins.entry(index).or_insert(0.0) += count;
+
+    while bins.len() > max_bins {
+        let (lowest, lowest_count) = bins.iter().next().map(|(i, c)| (*i, *c)).unwrap();
+        bins.remove(&lowest);
+        if let Some(next) = bins.values_mut().next() {
+            *next += lowest_count;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::DDSketch;
+
+    // The exact value at a quantile of a sorted slice, using the same rank as the sketch.
+    fn exact_quantile(values: &[f64], q: f64) -> f64 {
+        values[(q * (values.len() - 1) as f64) as usize]
+    }
+
+    #[test]
+    fn empty_sketch() {
+        let sketch = DDSketch::new(0.01, 2048);
+        assert!(sketch.is_empty());
+        assert_eq!(sketch.quantile(0.5), None);
+        assert_eq!(sketch.min(), None);
+    }
+
+    #[test]
+    fn quantiles_within_relative_accuracy() {
+        let mut sketch = DDSketch::new(0.01, 2048);
+        let values: Vec<f64> = (1..10_001).map(|v| f64::from(v) * 0.37).collect();
+        for v in &values {
+            sketch.insert(*v);
+        }
+
+        for q in &[0.0, 0.25, 0.5, 0.9, 0.99, 0.999, 1.0] {
+            let expected = exact_quantile(&values, *q);
+            let actual = sketch.quantile(*q).unwrap();
+            assert!((actual - expected).abs() <= 0.01 * expected,
+                    "quantile {} was {} instead of {}", q, actual, expected);
+        }
+        assert_eq!(sketch.count(), 10_000.0);
+        assert_eq!(sketch.min(), Some(0.37));
+    }
+
+    #[test]
+    fn negative_and_zero_values() {
+        let mut sketch = DDSketch::new(0.01, 2048);
+        for v in &[-100.0, -10.0, 0.0, 10.0, 100.0] {
+            sketch.insert(*v);
+        }
+
+        assert_eq!(sketch.quantile(0.0), Some(-100.0));
+        assert!((sketch.quantile(0.25).unwrap() + 10.0).abs() <= 0.1);
+        assert_eq!(sketch.quantile(0.5), Some(0.0));
+        assert!((sketch.quantile(0.75).unwrap() - 10.0).abs() <= 0.1);
+        assert_eq!(sketch.quantile(1.0), Some(100.0));
+        assert_eq!(sketch.sum(), 0.0);
+        assert_eq!(sketch.count_at_most(-50.0), 1.0);
+        assert_eq!(sketch.count_at_most(0.0), 3.0);
+        assert_eq!(sketch.count_at_most(1000.0), 5.0);
+    }
+
+    #[test]
+    fn exact_standard_deviation() {
+        let mut sketch = DDSketch::new(0.01, 2048);
+        for v in 1..6 {
+            sketch.insert(f64::from(v));
+        }
+
+        assert!((sketch.std_dev().unwrap() - 2f64.sqrt()).abs() < 1e-12);
+    }
+
+    #[test]
+    fn merged_sketch_matches_single_sketch() {
+        let mut single = DDSketch::new(0.02, 2048);
+        let mut left = DDSketch::new(0.02, 2048);
+        let mut right = DDSketch::new(0.02, 2048);
+        for v in 1..1001 {
+            let v = f64::from(v);
+            single.insert(v);
+            if v <= 300.0 {
+                left.insert(v);
+            } else {
+                right.insert_weighted(v, 1.0);
+            }
+        }
+
+        left.merge(&right);
+        for q in &[0.1, 0.5, 0.95] {
+            assert_eq!(left.quantile(*q), single.quantile(*q));
+        }
+        assert_eq!(left.count(), single.count());
+        assert_eq!(left.sum(), single.sum());
+        assert_eq!(left.max(), Some(1000.0));
+    }
+
+    #[test]
+    fn bins_are_exposed() {
+        let mut sketch = DDSketch::new(0.01, 2048);
+        for v in &[1.0, 2.0, 2.0, -1.0, 0.0] {
+            sketch.insert(*v);
+        }
+
+        let positive: Vec<(i32, f64)> = sketch.positive_bins().map(|(i, c)| (*i, *c)).collect();
+        let negative: Vec<(i32, f64)> = sketch.negative_bins().map(|(i, c)| (*i, *c)).collect();
+        assert_eq!(positive, vec![(0, 1.0), (35, 2.0)]);
+        assert_eq!(negative, vec![(0, 1.0)]);
+        assert_eq!(sketch.zero_count(), 1.0);
+    }
+
+    #[test]
+    fn bins_are_bounded() {
+        let mut sketch = DDSketch::new(0.01, 64);
+        for i in 0..200 {
+            sketch.insert(1.1f64.powi(i));
+        }
+
+        assert!(sketch.positive.len() <= 64);
+        assert_eq!(sketch.count(), 200.0);
+        let top = 1.1f64.powi(199);
+        assert!((sketch.quantile(1.0).unwrap() - top).abs() <= 0.01 * top);
+    }
+}
@@ -0,0 +1,115 @@
+//! The snapshot module defines what backends are handed on every flush. A snapshot is built once
+//! per flush interval from the cache, and backends can only read from it.
+#![deny(missing_docs)]
+
+use std::collections::{btree_map, hash_map, BTreeMap, HashMap};
+use std::slice;
+
+use cache::{BackendStats, MetricKey, Source, TimerStats};
+
+use parse::{Event, ServiceCheck};
+
+use sketch::DDSketch;
+
+/// `FlushSnapshot` holds everything aggregated during a single flush interval, with the timer
+/// statistics already computed.
+#[derive(Debug, Default)]
+pub struct FlushSnapshot {
+    pub(crate) timestamp: i64,
+    pub(crate) flush_interval: f64,
+    pub(crate) counters: HashMap<MetricKey, f64>,
+    pub(crate) gauges: HashMap<MetricKey, f64>,
+    pub(crate) sets: HashMap<MetricKey, f64>,
+    pub(crate) timer_data: HashMap<MetricKey, TimerStats>,
+    pub(crate) distributions: HashMap<MetricKey, DDSketch>,
+    pub(crate) events: Vec<Event>,
+    pub(crate) service_checks: Vec<ServiceCheck>,
+    pub(crate) metrics_seen: u64,
+    pub(crate) bad_metrics: u64,
+    pub(crate) bad_metric_reasons: BTreeMap<&'static str, u64>,
+    pub(crate) bad_sources: BTreeMap<Source, u64>,
+    pub(crate) backend_stats: BTreeMap<&'static str, BackendStats>,
+}
+
+impl FlushSnapshot {
+    /// Return the time of the flush in seconds since the Unix epoch.
+    #[inline]
+    pub fn timestamp(&self) -> i64 {
+        self.timestamp
+    }
+
+    /// Return the total number of metrics seen.
+    #[inline]
+    pub fn total_metrics(&self) -> f64 {
+        self.metrics_seen as f64
+    }
+
+    /// Return the total number of failed metrics seen.
+    #[inline]
+    pub fn total_bad_metrics(&self) -> f64 {
+        self.bad_metrics as f64
+    }
+
+    /// Return an iterator over the number of failed metrics seen for each reason, ordered by the
+    /// reason.
+    pub fn bad_metrics_iter(&self) -> btree_map::Iter<'_, &'static str, u64> {
+        self.bad_metric_reasons.iter()
+    }
+
+    /// Return an iterator over the number of failed metrics seen from each source, ordered by the
+    /// source.
+    pub fn bad_sources_iter(&self) -> btree_map::Iter<'_, Source, u64> {
+        self.bad_sources.iter()
+    }
+
+    /// Return an iterator over the flushes each backend finished during the interval, ordered by
+    /// the name of the backend.
+    pub fn backend_stats_iter(&self) -> btree_map::Iter<'_, &'static str, BackendStats> {
+        self.backend_stats.iter()
+    }
+
+    /// Return an iterator over the counters.
+    pub fn counters_iter(&self) -> hash_map::Iter<'_, MetricKey, f64> {
+        self.counters.iter()
+    }
+
+    /// Return an iterator over the per second rate of each counter over the flush interval, in
+    /// the same order as `counters_iter`.
+    pub fn counter_rates_iter(&self) -> impl Iterator<Item = (&MetricKey, f64)> {
+        let interval = self.flush_interval;
+        self.counters.iter().map(move |(k, v)| (k, v / interval))
+    }
+
+    /// Return an iterator over the gauges.
+    pub fn gauges_iter(&self) -> hash_map::Iter<'_, MetricKey, f64> {
+        self.gauges.iter()
+    }
+
+    /// Return an iterator over the number of distinct members of each set.
+    pub fn sets_iter(&self) -> hash_map::Iter<'_, MetricKey, f64> {
+        self.sets.iter()
+    }
+
+    /// Return an iterator over the statistics of each timer, histogram and distribution.
+    pub fn timer_data_iter(&self) -> hash_map::Iter<'_, MetricKey, TimerStats> {
+        self.timer_data.iter()
+    }
+
+    /// Return an iterator over the sketches behind each distribution, so that backends can export
+    /// them to be merged with sketches from other aggregators.
+    pub fn distributions_iter(&self) -> hash_map::Iter<'_, MetricKey, DDSketch> {
+        self.distributions.iter()
+    }
+
+    /// Return an iterator over the events received during the interval, in the order they
+    /// arrived.
+    pub fn events_iter(&self) -> slice::Iter<'_, Event> {
+        self.events.iter()
+    }
+
+    /// Return an iterator over the service checks received during the interval, in the order
+    /// they arrived.
+    pub fn service_checks_iter(&self) -> slice::Iter<'_, ServiceCheck> {
+        self.service_checks.iter()
+    }
+}
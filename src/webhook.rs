@@ -0,0 +1,287 @@
+//! The webhook module is a backend that forwards DogStatsD events and service checks to an HTTP
+//! endpoint as JSON, along with the sketches behind distributions. Other metrics are not
+//! forwarded.
+#![deny(missing_docs)]
+
+use std::collections::btree_map;
+use std::fmt::Write;
+use std::io;
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::time::Duration;
+
+use futures::future;
+
+use tokio_core::reactor::Handle;
+
+use backend::{done, not_started, post_http, Backend, Flush, DEFAULT_CONNECT_TIMEOUT_SECS,
+              DEFAULT_WRITE_TIMEOUT_SECS};
+
+use snapshot::FlushSnapshot;
+
+use cache::MetricKey;
+
+use parse::{Event, ServiceCheck, Tag};
+
+use sketch::DDSketch;
+
+/// The backend that posts events, service checks and distribution sketches to a webhook.
+#[derive(Debug)]
+pub struct Webhook {
+    addr: SocketAddr,
+    host: String,
+    path: String,
+    connect_timeout: Duration,
+    write_timeout: Duration,
+    handle: Option<Handle>,
+}
+
+impl Webhook {
+    /// Construct a new webhook instance from a URL such as `http://127.0.0.1:8080/events`.
+    pub fn new(url: &str) -> io::Result<Webhook> {
+        let invalid = || io::Error::new(io::ErrorKind::InvalidInput, "invalid webhook url");
+
+        if !url.starts_with("http://") {
+            return Err(invalid());
+        }
+        let rest = &url["http://".len()..];
+        let (authority, path) = match rest.find('/') {
+            Some(i) => (&rest[..i], &rest[i..]),
+            None => (rest, "/"),
+        };
+
+        Ok(Webhook {
+            addr: authority.to_socket_addrs()?.next().ok_or_else(invalid)?,
+            host: String::from(authority),
+            path: String::from(path),
+            connect_timeout: Duration::from_secs(DEFAULT_CONNECT_TIMEOUT_SECS),
+            write_timeout: Duration::from_secs(DEFAULT_WRITE_TIMEOUT_SECS),
+            handle: None,
+        })
+    }
+
+    /// Set how long a flush may take to connect to the webhook, and then how long it may take to
+    /// post the body and read the response. Both default to 5 seconds.
+    pub fn with_timeouts(mut self, connect_timeout: Duration, write_timeout: Duration) -> Webhook {
+        self.connect_timeout = connect_timeout;
+        self.write_timeout = write_timeout;
+        self
+    }
+
+    // Build the JSON body for a flush, or nothing if there is nothing to forward. Distributions
+    // that saw no values during the interval are left out.
+    fn make_body(&self, snapshot: &FlushSnapshot) -> Option<String> {
+        let events: Vec<String> = snapshot.events_iter().map(event_json).collect();
+        let checks: Vec<String> = snapshot.service_checks_iter().map(service_check_json).collect();
+        let distributions: Vec<String> = snapshot.distributions_iter()
+            .filter(|&(_, sketch)| !sketch.is_empty())
+            .map(|(key, sketch)| distribution_json(key, sketch))
+            .collect();
+        if events.is_empty() && checks.is_empty() && distributions.is_empty() {
+            return None;
+        }
+
+        Some(format!("{{\"events\":[{}],\"service_checks\":[{}],\"distributions\":[{}]}}",
+                     events.join(","),
+                     checks.join(","),
+                     distributions.join(",")))
+    }
+}
+
+impl Backend for Webhook {
+    fn name(&self) -> &'static str {
+        "webhook"
+    }
+
+    fn start(&mut self, handle: &Handle) {
+        self.handle = Some(handle.clone());
+    }
+
+    // Distributions are posted along with the events so that every flush is a single request.
+    fn purge_events(&self, snapshot: &FlushSnapshot) -> Flush {
+        let body = match self.make_body(snapshot) {
+            Some(body) => body,
+            None => return done(),
+        };
+
+        match self.handle {
+            Some(ref handle) => {
+                let timeouts = (self.connect_timeout, self.write_timeout);
+                post_http(self.addr, &self.host, &self.path, "application/json", &body, timeouts,
+                          handle)
+            }
+            None => Box::new(future::err(not_started())),
+        }
+    }
+
+    fn purge_metrics(&self, _snapshot: &FlushSnapshot) -> Flush {
+        done()
+    }
+}
+
+fn event_json(event: &Event) -> String {
+    let mut json = format!("{{\"title\":{},\"text\":{}",
+                           json_string(&event.title),
+                           json_string(&event.text));
+    if let Some(timestamp) = event.timestamp {
+        write!(json, ",\"timestamp\":{}", timestamp).unwrap();
+    }
+    push_optional(&mut json, "hostname", &event.hostname);
+    push_optional(&mut json, "aggregation_key", &event.aggregation_key);
+    push_optional(&mut json, "priority", &event.priority);
+    push_optional(&mut json, "source_type", &event.source_type);
+    push_optional(&mut json, "alert_type", &event.alert_type);
+    write!(json, ",\"tags\":{}}}", tags_json(&event.tags)).unwrap();
+
+    json
+}
+
+fn service_check_json(check: &ServiceCheck) -> String {
+    let mut json = format!("{{\"name\":{},\"status\":{}",
+                           json_string(&check.name),
+                           check.status.code());
+    if let Some(timestamp) = check.timestamp {
+        write!(json, ",\"timestamp\":{}", timestamp).unwrap();
+    }
+    push_optional(&mut json, "hostname", &check.hostname);
+    push_optional(&mut json, "message", &check.message);
+    write!(json, ",\"tags\":{}}}", tags_json(&check.tags)).unwrap();
+
+    json
+}
+
+// A sketch is written with its exact statistics and its bins, as `[index, count]` pairs, so that
+// sketches with the same relative accuracy can be merged by whoever receives them.
+fn distribution_json(key: &MetricKey, sketch: &DDSketch) -> String {
+    format!("{{\"name\":{},\"tags\":{},\"relative_accuracy\":{},\"count\":{},\"sum\":{},\
+             \"min\":{},\"max\":{},\"zero\":{},\"positive\":{},\"negative\":{}}}",
+            json_string(key.name()),
+            tags_json(key.tags()),
+            sketch.relative_accuracy(),
+            sketch.count(),
+            sketch.sum(),
+            sketch.min().unwrap(),
+            sketch.max().unwrap(),
+            sketch.zero_count(),
+            bins_json(sketch.positive_bins()),
+            bins_json(sketch.negative_bins()))
+}
+
+fn bins_json(bins: btree_map::Iter<'_, i32, f64>) -> String {
+    let bins: Vec<String> = bins.map(|(index, count)| format!("[{},{}]", index, count)).collect();
+    format!("[{}]", bins.join(","))
+}
+
+// Fields that were not sent are left out rather than written as null.
+fn push_optional(json: &mut String, name: &str, value: &Option<String>) {
+    if let Some(ref value) = *value {
+        write!(json, ",\"{}\":{}", name, json_string(value)).unwrap();
+    }
+}
+
+// Tags are written the way DogStatsD clients send them, as `name` or `name:value` strings.
+fn tags_json(tags: &[Tag]) -> String {
+    let tags: Vec<String> = tags.iter()
+        .map(|t| match t.value {
+            Some(ref value) => json_string(&format!("{}:{}", t.name, value)),
+            None => json_string(&t.name),
+        })
+        .collect();
+    format!("[{}]", tags.join(","))
+}
+
+fn json_string(s: &str) -> String {
+    let mut json = String::with_capacity(s.len() + 2);
+    json.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            '\n' => json.push_str("\\n"),
+            '\r' => json.push_str("\\r"),
+            '\t' => json.push_str("\\t"),
+            c if (c as u32) < 0x20 => write!(json, "\\u{:04x}", c as u32).unwrap(),
+            c => json.push(c),
+        }
+    }
+    json.push('"');
+
+    json
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::ErrorKind;
+    use std::net::TcpListener;
+    use std::time::Duration;
+
+    use tokio_core::reactor::Core;
+
+    use super::{json_string, Webhook};
+    use backend::Backend;
+    use cache::CapellaCache;
+    use parse::parse_line;
+
+    #[test]
+    fn strings_are_escaped() {
+        assert_eq!(json_string("a \"b\"\\\n\u{1}"), "\"a \\\"b\\\"\\\\\\n\\u0001\"");
+    }
+
+    #[test]
+    fn events_and_checks_body() {
+        let webhook = Webhook::new("http://127.0.0.1:8080/events").unwrap();
+        let mut cache = CapellaCache::default();
+        assert_eq!(webhook.make_body(&cache.flush(0)), None);
+
+        for line in &[&b"_e{6,6}:deploy|v2\\nok|h:web-1|#env:prod,canary"[..],
+                      b"_sc|db.up|2|d:1500000000|m:replica \"b\" lagging",
+                      b"hits:1|c"] {
+            cache.add_line(&parse_line(line).unwrap());
+        }
+
+        assert_eq!(webhook.make_body(&cache.flush(0)).unwrap(),
+                   "{\"events\":[{\"title\":\"deploy\",\"text\":\"v2\\nok\",\
+                    \"hostname\":\"web-1\",\"tags\":[\"env:prod\",\"canary\"]}],\
+                    \"service_checks\":[{\"name\":\"db.up\",\"status\":2,\
+                    \"timestamp\":1500000000,\"message\":\"replica \\\"b\\\" lagging\",\
+                    \"tags\":[]}],\"distributions\":[]}");
+    }
+
+    #[test]
+    fn distributions_body() {
+        let webhook = Webhook::new("http://127.0.0.1:8080/events").unwrap();
+        let mut cache = CapellaCache::default();
+        for line in &[&b"lag:1|d|#env:prod"[..], b"lag:2|d|#env:prod", b"lag:2|d|#env:prod"] {
+            cache.add_line(&parse_line(line).unwrap());
+        }
+
+        assert_eq!(webhook.make_body(&cache.flush(0)).unwrap(),
+                   "{\"events\":[],\"service_checks\":[],\"distributions\":[{\"name\":\"lag\",\
+                    \"tags\":[\"env:prod\"],\"relative_accuracy\":0.01,\"count\":3,\"sum\":5,\
+                    \"min\":1,\"max\":2,\"zero\":0,\"positive\":[[0,1],[35,2]],\"negative\":[]}]}");
+    }
+
+    #[test]
+    fn urls() {
+        let webhook = Webhook::new("http://127.0.0.1:8080").unwrap();
+        assert_eq!(webhook.path, "/");
+        assert_eq!(webhook.host, "127.0.0.1:8080");
+        assert!(Webhook::new("https://127.0.0.1:8080/events").is_err());
+    }
+
+    #[test]
+    fn unanswered_posts_time_out() {
+        // The listener accepts the connection but never sends a response.
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/events", listener.local_addr().unwrap());
+        let mut webhook = Webhook::new(&url)
+            .unwrap()
+            .with_timeouts(Duration::from_secs(5), Duration::from_millis(50));
+
+        let mut cache = CapellaCache::default();
+        cache.add_line(&parse_line(b"_e{6,2}:deploy|ok").unwrap());
+        let mut core = Core::new().unwrap();
+        webhook.start(&core.handle());
+        let res = core.run(webhook.purge_events(&cache.flush(0)));
+        assert_eq!(res.unwrap_err().kind(), ErrorKind::TimedOut);
+    }
+}